@@ -0,0 +1,89 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use rust_beiboot::core::accumulator::{Accumulator, BlendSettings};
+use rust_beiboot::core::long_exposure_image_logic::render_frames;
+use rust_beiboot::core::output::{to_rgba16, to_rgba8};
+use rust_beiboot::models::{BlendColorSpace, BlendMode, FrameData, WeightingSettings};
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+
+/// Deterministic frame with gradients and some noise, so no branch of the kernels is trivially predictable.
+fn synthetic_frame(seed: u32) -> RgbaImage {
+    ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        let noise = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663) ^ seed.wrapping_mul(83_492_791)) % 64;
+        Rgba([
+            ((x + seed * 7) % 256) as u8,
+            ((y + seed * 13) % 256) as u8,
+            ((x / 4 + y / 4 + noise) % 256) as u8,
+            255,
+        ])
+    })
+}
+
+fn settings(blend_mode: BlendMode) -> BlendSettings {
+    BlendSettings {
+        blend_mode,
+        weighting: WeightingSettings::default(),
+        color_space: BlendColorSpace::Linear,
+    }
+}
+
+fn accumulate_frame(c: &mut Criterion) {
+    let frame = synthetic_frame(1);
+    let mut group = c.benchmark_group("accumulate_frame");
+    group.throughput(Throughput::Elements(WIDTH as u64 * HEIGHT as u64));
+    for blend_mode in [BlendMode::WeightedMean, BlendMode::Lighten, BlendMode::Screen, BlendMode::Additive] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", blend_mode)),
+            &blend_mode,
+            |b, blend_mode| {
+                let mut accumulator: Accumulator = Accumulator::new(WIDTH, HEIGHT, settings(*blend_mode));
+                b.iter(|| accumulator.add_frame(black_box(&frame), 0.5));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn render_stack(c: &mut Criterion) {
+    let frames: Vec<RgbaImage> = (0..8).map(synthetic_frame).collect();
+    let frames_data: Vec<FrameData> = (0..frames.len())
+        .map(|frame_number| FrameData {
+            frame_number,
+            frame_weight: 1.0,
+            freeze: false,
+        })
+        .collect();
+    let load = |frame_number: usize| Ok(frames[frame_number].clone());
+
+    let mut group = c.benchmark_group("render_frames");
+    group.sample_size(10);
+    for blend_mode in [BlendMode::WeightedMean, BlendMode::Median] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", blend_mode)),
+            &blend_mode,
+            |b, blend_mode| {
+                b.iter(|| render_frames(&frames_data, &settings(*blend_mode), (WIDTH, HEIGHT), load).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+fn encode_output(c: &mut Criterion) {
+    let frame = synthetic_frame(2);
+    let mut accumulator: Accumulator = Accumulator::new(WIDTH, HEIGHT, settings(BlendMode::WeightedMean));
+    accumulator.add_frame(&frame, 1.0);
+    let long_exposure_img = accumulator.finish();
+
+    let mut group = c.benchmark_group("encode_output");
+    group.throughput(Throughput::Elements(WIDTH as u64 * HEIGHT as u64));
+    group.bench_function("to_rgba8", |b| b.iter(|| to_rgba8(black_box(long_exposure_img.clone()))));
+    group.bench_function("to_rgba16", |b| b.iter(|| to_rgba16(black_box(long_exposure_img.clone()))));
+    group.finish();
+}
+
+criterion_group!(benches, accumulate_frame, render_stack, encode_output);
+criterion_main!(benches);
@@ -3,7 +3,8 @@ use std::path::Path;
 use std::str::FromStr;
 
 use actix_multipart::Multipart;
-use actix_web::{delete, get, HttpResponse, post, web};
+use actix_web::{delete, get, HttpRequest, HttpResponse, post, web};
+use actix_web::http::header;
 use actix_web::web::BytesMut;
 use futures_util::TryStreamExt;
 use serde_json::json;
@@ -11,8 +12,15 @@ use tracing::error;
 use tracing::log::info;
 use uuid::Uuid;
 
-use crate::models::{CreateLongExposureImageRequest, GetProjectsResponse};
-use crate::services::long_exposure_image_service::create_long_exposure_image_svc;
+use crate::error::{RenderError, ServiceError};
+use crate::models::{
+    CreateLongExposureImageRequest, CreateLongExposureImageVariantsRequest, GetMasksResponse, GetProjectsResponse,
+    UploadMaskResponse,
+};
+use crate::services::long_exposure_image_service::{
+    create_long_exposure_image_svc, create_long_exposure_image_variants_svc,
+};
+use crate::services::mask_service::{list_project_masks, save_project_mask};
 use crate::services::projects_service::{delete_project_by_id, fetch_projects, process_upload};
 use crate::utils::{read_metadata_from_project, read_text_from_field};
 
@@ -119,21 +127,40 @@ pub async fn create_or_update_project(mut payload: Multipart) -> HttpResponse {
     }
 }
 
+/**
+Renders a long exposure image. The response body is the URL of the image as plain text, clients
+sending `Accept: application/json` get a `CreateLongExposureImageResponse` with the rejection
+statistics and the animation URL instead.
+*/
 #[post("/projects/{project_id}/createLongExposureImage")]
 pub async fn create_long_exposure_image(
+    http_request: HttpRequest,
     path: web::Path<String>,
     request_body: web::Json<CreateLongExposureImageRequest>,
 ) -> HttpResponse {
     let project_id = path.into_inner();
     let image_request = request_body.into_inner();
 
-    match create_long_exposure_image_svc(
-        project_id,
-        image_request.frames_to_include,
-    )
-        .await
+    match create_long_exposure_image_svc(project_id, image_request).await
     {
-        Ok(path_to_long_exposure_img) => HttpResponse::Ok().body(path_to_long_exposure_img),
+        Ok(response) if accepts_json(&http_request) => HttpResponse::Ok().json(response),
+        Ok(response) => HttpResponse::Ok().body(response.image_url),
+        Err(ServiceError::CreateImageError(ref e @ RenderError::InvalidRequest(_))) => {
+            HttpResponse::BadRequest().json(json!({
+                "message": "The long exposure image could not be rendered.",
+                "error": format!("{}", e),
+            }))
+        }
+        Err(ServiceError::CreateImageError(
+            ref e @ RenderError::DimensionMismatch { ref expected, ref frames },
+        )) => {
+            HttpResponse::UnprocessableEntity().json(json!({
+                "message": "Some frames do not have the same dimensions as the first selected frame.",
+                "error": format!("{}", e),
+                "expected_dimensions": expected,
+                "mismatched_frames": frames,
+            }))
+        }
         Err(e) => {
             error!("Error creating long exposure image: {:?}", e);
             HttpResponse::InternalServerError().json(
@@ -144,4 +171,112 @@ pub async fn create_long_exposure_image(
             )
         }
     }
-}
\ No newline at end of file
+}
+
+/// Whether the client asked for a JSON response in its `Accept` header.
+fn accepts_json(http_request: &HttpRequest) -> bool {
+    http_request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+/**
+Renders several variants of a request in one pass over the frames. Each variant lists the fields it
+changes in the base request, optionally a contact sheet with all variants is rendered as well.
+*/
+#[post("/projects/{project_id}/createLongExposureImageVariants")]
+pub async fn create_long_exposure_image_variants(
+    path: web::Path<String>,
+    request_body: web::Json<CreateLongExposureImageVariantsRequest>,
+) -> HttpResponse {
+    let project_id = path.into_inner();
+    let variants_request = request_body.into_inner();
+
+    match create_long_exposure_image_variants_svc(project_id, variants_request).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(ServiceError::CreateImageError(ref e @ RenderError::InvalidRequest(_))) => {
+            HttpResponse::BadRequest().json(json!({
+                "message": "The variants could not be rendered.",
+                "error": format!("{}", e),
+            }))
+        }
+        Err(ServiceError::CreateImageError(
+            ref e @ RenderError::DimensionMismatch { ref expected, ref frames },
+        )) => {
+            HttpResponse::UnprocessableEntity().json(json!({
+                "message": "Some frames do not have the same dimensions as the first selected frame.",
+                "error": format!("{}", e),
+                "expected_dimensions": expected,
+                "mismatched_frames": frames,
+            }))
+        }
+        Err(e) => {
+            error!("Error creating long exposure image variants: {:?}", e);
+            HttpResponse::InternalServerError().json(
+                json!({
+                    "message": "An internal server error occurred while trying to create the long exposure image variants.",
+                    "error": format!("{}", e)
+                })
+            )
+        }
+    }
+}
+
+/**
+Uploads a grayscale mask for a project as multipart form data with the fields `mask_name` and `mask_file`.
+Renders of the project can restrict the long exposure blend to the white areas of the mask by referring to its name.
+*/
+#[post("/projects/{project_id}/masks")]
+pub async fn upload_mask(path: web::Path<String>, mut payload: Multipart) -> HttpResponse {
+    let project_id = path.into_inner();
+    let mut mask_name = String::new();
+    let mut mask_data: Option<BytesMut> = None;
+
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        let name = field.content_disposition().get_name().unwrap_or_default().to_string();
+
+        match name.as_str() {
+            "mask_name" => {
+                mask_name = read_text_from_field(field).await;
+            }
+            "mask_file" => {
+                let mut data = BytesMut::new();
+                while let Ok(Some(chunk)) = field.try_next().await {
+                    data.extend_from_slice(&chunk);
+                }
+                mask_data = Some(data);
+            }
+            _ => error!("Unexpected field: {}", name),
+        }
+    }
+
+    let Some(mask_data) = mask_data else {
+        return HttpResponse::BadRequest().body("No mask_file was uploaded");
+    };
+
+    match save_project_mask(&project_id, &mask_name, &mask_data).await {
+        Ok(mask_name) => HttpResponse::Ok().json(UploadMaskResponse {
+            message: "Mask uploaded successfully",
+            mask_name,
+        }),
+        Err(ServiceError::InvalidInput(message)) => HttpResponse::BadRequest().body(message),
+        Err(err) => {
+            error!("An error occurred while uploading the mask: {}", err);
+            HttpResponse::InternalServerError().body("An error occurred while uploading the mask")
+        }
+    }
+}
+
+#[get("/projects/{project_id}/masks")]
+pub async fn get_masks(path: web::Path<String>) -> HttpResponse {
+    let project_id = path.into_inner();
+    match list_project_masks(&project_id).await {
+        Ok(masks) => HttpResponse::Ok().json(GetMasksResponse { masks }),
+        Err(err) => {
+            error!("An error occurred while fetching masks: {}", err);
+            HttpResponse::InternalServerError().body("An error occurred while fetching masks")
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::ops::Add;
+
+use image::{ImageBuffer, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::core::weighting::PixelWeighting;
+use crate::models::{BlendColorSpace, BlendMode, FrameData, TrailFade, WeightingSettings};
+
+/// Number type of the accumulation buffers. `f32` keeps memory low, `f64` allows
+/// removing frames again without the sums drifting. Still renders of reversible blend modes keep
+/// their state for incremental updates and therefore accumulate in `f64`, every other render of a
+/// reversible mode (tiles, variants, animations) does the same so its result matches the still image.
+pub trait AccumulatorValue: Copy + Send + Sync + Add<Output = Self> {
+    fn from_f32(value: f32) -> Self;
+    fn to_f32(self) -> f32;
+}
+
+impl AccumulatorValue for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+impl AccumulatorValue for f64 {
+    fn from_f32(value: f32) -> Self {
+        value as f64
+    }
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+}
+
+/// Everything that decides how a single frame contributes to a render.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlendSettings {
+    pub blend_mode: BlendMode,
+    pub weighting: WeightingSettings,
+    pub color_space: BlendColorSpace,
+}
+
+impl BlendSettings {
+    /// Whether 8-bit output truncates instead of rounding. Weighted means blended in sRGB did so
+    /// before frames were blended in linear light, so those renders stay the same.
+    pub fn truncates_8bit_output(&self) -> bool {
+        self.color_space == BlendColorSpace::Srgb && self.blend_mode == BlendMode::WeightedMean
+    }
+}
+
+/**
+
+Running per-pixel state of a long exposure render.
+Values are kept in the blend color space, `finish` converts them to linear light.
+Frames are added one after another and can be dropped right after, so the memory needed
+stays the same no matter how many frames are blended.
+Only blend modes that can be computed from a running value are supported, see
+`BlendMode::is_streamable`.
+
+ */
+#[derive(Clone)]
+pub struct Accumulator<T: AccumulatorValue = f32> {
+    width: u32,
+    height: u32,
+    settings: BlendSettings,
+    weighting: PixelWeighting,
+    decoding_table: [f32; 256],
+    buffer: Vec<T>,
+}
+
+impl BlendMode {
+    /// Whether the mode can be computed by adding frames one by one to an `Accumulator`.
+    /// Other modes need all samples of a pixel at once or, like time slices, place frames
+    /// side by side.
+    pub fn is_streamable(&self) -> bool {
+        !matches!(
+            self,
+            BlendMode::Median
+                | BlendMode::SigmaClipping { .. }
+                | BlendMode::Stroboscopic { .. }
+                | BlendMode::TimeSlice { .. }
+        )
+    }
+
+    /// Whether frames can be removed from an `Accumulator` again, because their
+    /// contribution is a plain sum.
+    pub fn is_reversible(&self) -> bool {
+        matches!(self, BlendMode::WeightedMean | BlendMode::Additive)
+    }
+
+    /// Number of values the accumulator keeps per pixel.
+    fn accumulator_stride(&self) -> usize {
+        match self {
+            // Weighted color, weighted alpha, the sum of weights and the number of
+            // contributing samples, which tells a removed pixel apart from rounding noise
+            BlendMode::WeightedMean => 6,
+            _ => 4,
+        }
+    }
+
+    /// Value the color channels of the accumulator start with.
+    fn initial_value(&self) -> f32 {
+        match self {
+            BlendMode::Darken | BlendMode::Screen => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/**
+
+Converts the user-specified frame weights into the factor each frame is accumulated with.
+The weighted mean and median use the weights as they are, layer-like modes use the weight
+as opacity relative to the strongest frame and additive uses the raw weight as gain.
+Light trails additionally fade each frame by its distance in frames to the end the trail
+starts from, which makes the frame order matter.
+
+ */
+pub fn frame_factors(blend_mode: BlendMode, frames_data: &[FrameData]) -> Vec<f32> {
+    let max_weight = frames_data
+        .iter()
+        .map(|frame| frame.frame_weight)
+        .fold(0.0f32, f32::max);
+    let relative_weight = |weight: f32| {
+        if max_weight > 0.0 {
+            weight / max_weight
+        } else {
+            0.0
+        }
+    };
+    let first_frame = frames_data.iter().map(|frame| frame.frame_number).min().unwrap_or(0);
+    let last_frame = frames_data.iter().map(|frame| frame.frame_number).max().unwrap_or(0);
+
+    frames_data
+        .iter()
+        .map(|frame| match blend_mode {
+            BlendMode::Lighten | BlendMode::Darken | BlendMode::Screen => {
+                relative_weight(frame.frame_weight)
+            }
+            BlendMode::LightTrails { half_life, fade } => {
+                let age = match fade {
+                    TrailFade::TowardFirst => last_frame - frame.frame_number,
+                    TrailFade::TowardLast => frame.frame_number - first_frame,
+                };
+                let decay = if half_life > 0.0 {
+                    0.5f32.powf(age as f32 / half_life)
+                } else {
+                    1.0
+                };
+                relative_weight(frame.frame_weight) * decay
+            }
+            _ => frame.frame_weight,
+        })
+        .collect()
+}
+
+impl<T: AccumulatorValue> Accumulator<T> {
+    pub fn new(width: u32, height: u32, settings: BlendSettings) -> Self {
+        let blend_mode = settings.blend_mode;
+        let stride = blend_mode.accumulator_stride();
+        let mut pixel = vec![T::from_f32(blend_mode.initial_value()); stride];
+        // Alpha always starts transparent, weight and sample count at zero
+        for value in pixel.iter_mut().skip(3) {
+            *value = T::from_f32(0.0);
+        }
+        let buffer = pixel
+            .iter()
+            .copied()
+            .cycle()
+            .take(width as usize * height as usize * stride)
+            .collect();
+
+        Accumulator {
+            width,
+            height,
+            weighting: settings.weighting.prepare(),
+            decoding_table: settings.color_space.decoding_table(),
+            settings,
+            buffer,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn settings(&self) -> &BlendSettings {
+        &self.settings
+    }
+
+    /**
+
+    Adds one frame to the running state. The rows of the frame are processed in parallel.
+    # Arguments
+    - `frame`: The decoded frame, it has to have the same dimensions as the accumulator.
+    - `factor`: The factor of the frame as returned by `frame_factors`.
+
+     */
+    pub fn add_frame(&mut self, frame: &RgbaImage, factor: f32) {
+        self.apply_frame(frame, factor, 1.0);
+    }
+
+    /// Removes a frame that was previously added with the same factor.
+    /// Only valid for modes where `BlendMode::is_reversible` holds.
+    pub fn remove_frame(&mut self, frame: &RgbaImage, factor: f32) {
+        debug_assert!(self.settings.blend_mode.is_reversible());
+        self.apply_frame(frame, -factor, -1.0);
+    }
+
+    /// Changes the factor of a frame that is already part of the accumulator.
+    /// Only valid for modes where `BlendMode::is_reversible` holds.
+    pub fn reweight_frame(&mut self, frame: &RgbaImage, old_factor: f32, new_factor: f32) {
+        debug_assert!(self.settings.blend_mode.is_reversible());
+        self.apply_frame(frame, new_factor - old_factor, 0.0);
+    }
+
+    fn apply_frame(&mut self, frame: &RgbaImage, factor: f32, sample_count_delta: f32) {
+        debug_assert_eq!(frame.dimensions(), self.dimensions());
+        let stride = self.settings.blend_mode.accumulator_stride();
+        let row_len = self.width as usize;
+        let blend_mode = self.settings.blend_mode;
+        let weighting = &self.weighting;
+        let decoding_table = &self.decoding_table;
+
+        // The blend mode is matched once per row, so the pixel loops stay free of branches on it
+        self.buffer
+            .par_chunks_mut(row_len * stride)
+            .zip(frame.as_raw().par_chunks(row_len * 4))
+            .for_each(|(accum_row, frame_row)| match blend_mode {
+                BlendMode::WeightedMean => accumulate_weighted_row(
+                    weighting,
+                    decoding_table,
+                    accum_row,
+                    frame_row,
+                    factor,
+                    sample_count_delta,
+                ),
+                BlendMode::Additive => accumulate_additive_row(
+                    weighting,
+                    decoding_table,
+                    accum_row,
+                    frame_row,
+                    factor,
+                    sample_count_delta,
+                ),
+                // Weaker frames are pulled towards black so they are less likely to win
+                BlendMode::Lighten | BlendMode::LightTrails { .. } => {
+                    accumulate_layer_row(weighting, decoding_table, accum_row, frame_row, factor, |current, value, opacity| {
+                        current.max(value * opacity)
+                    })
+                }
+                // Weaker frames are pulled towards white so they are less likely to win
+                BlendMode::Darken => {
+                    accumulate_layer_row(weighting, decoding_table, accum_row, frame_row, factor, |current, value, opacity| {
+                        current.min((1.0 - opacity * (1.0 - value)).max(0.0))
+                    })
+                }
+                // Stores the product of the inverted values, inverted again in `finish`. Boosted
+                // samples are clamped, a negative factor would flip the sign of the product
+                BlendMode::Screen => {
+                    accumulate_layer_row(weighting, decoding_table, accum_row, frame_row, factor, |current, value, opacity| {
+                        current * (1.0 - value * opacity).max(0.0)
+                    })
+                }
+                BlendMode::Median
+                | BlendMode::SigmaClipping { .. }
+                | BlendMode::Stroboscopic { .. }
+                | BlendMode::TimeSlice { .. } => {}
+            });
+    }
+
+    /// Turns the running state into the final linear light image with channel values normalized
+    /// to `[0,1]`.
+    /// Additive blending can produce values above 1.
+    pub fn finish(&self) -> Rgba32FImage {
+        let stride = self.settings.blend_mode.accumulator_stride();
+        let blend_mode = self.settings.blend_mode;
+        let mut long_exposure_img: Rgba32FImage = ImageBuffer::new(self.width, self.height);
+
+        long_exposure_img
+            .par_chunks_mut(4)
+            .zip(self.buffer.par_chunks(stride))
+            .for_each(|(out, accum)| match blend_mode {
+                BlendMode::WeightedMean => {
+                    let weight_accum = accum[4].to_f32();
+                    let sample_count = accum[5].to_f32();
+                    if sample_count > 0.5 && weight_accum > 0.0 {
+                        for (channel, value) in out.iter_mut().zip(accum) {
+                            *channel = value.to_f32() / weight_accum;
+                        }
+                    }
+                }
+                BlendMode::Screen => {
+                    for (channel, value) in out[..3].iter_mut().zip(accum) {
+                        *channel = 1.0 - value.to_f32();
+                    }
+                    out[3] = accum[3].to_f32();
+                }
+                _ => {
+                    for (channel, value) in out.iter_mut().zip(accum) {
+                        *channel = value.to_f32();
+                    }
+                }
+            });
+
+        self.settings.color_space.convert_to_linear(&mut long_exposure_img);
+        long_exposure_img
+    }
+}
+
+fn accumulate_weighted_row<T: AccumulatorValue>(
+    weighting: &PixelWeighting,
+    decoding_table: &[f32; 256],
+    accum_row: &mut [T],
+    frame_row: &[u8],
+    factor: f32,
+    sample_count_delta: f32,
+) {
+    for (accum, pixel) in accum_row.chunks_exact_mut(6).zip(frame_row.chunks_exact(4)) {
+        // Normalize alpha to [0,1]
+        let alpha = pixel[3] as f32 / 255.0;
+        // Adjust pixel weight using the perceived (sRGB) brightness
+        let brightness_weight = weighting.pixel_weight(pixel);
+        if alpha * brightness_weight == 0.0 {
+            continue;
+        }
+        let pixel_weight = factor * alpha * brightness_weight;
+
+        // Accumulate weighted color values
+        for (channel, value) in accum[..3].iter_mut().zip(pixel) {
+            *channel = *channel + T::from_f32(decoding_table[*value as usize] * pixel_weight);
+        }
+        accum[3] = accum[3] + T::from_f32(alpha * pixel_weight); // Accumulate alpha
+        accum[4] = accum[4] + T::from_f32(pixel_weight); // Accumulate weight
+        accum[5] = accum[5] + T::from_f32(sample_count_delta);
+    }
+}
+
+fn accumulate_additive_row<T: AccumulatorValue>(
+    weighting: &PixelWeighting,
+    decoding_table: &[f32; 256],
+    accum_row: &mut [T],
+    frame_row: &[u8],
+    factor: f32,
+    sample_count_delta: f32,
+) {
+    for (accum, pixel) in accum_row.chunks_exact_mut(4).zip(frame_row.chunks_exact(4)) {
+        let alpha = pixel[3] as f32 / 255.0;
+        let opacity = factor * alpha * weighting.key_boost(pixel);
+        for (channel, value) in accum[..3].iter_mut().zip(pixel) {
+            *channel = *channel + T::from_f32(decoding_table[*value as usize] * opacity);
+        }
+        // Alpha is only tracked while adding, removed frames keep it as it was
+        if sample_count_delta > 0.0 {
+            accum[3] = T::from_f32(accum[3].to_f32().max(alpha));
+        }
+    }
+}
+
+/// Layer-like modes, where `blend` combines the current value, the decoded sample and the opacity
+/// of the sample.
+fn accumulate_layer_row<T: AccumulatorValue>(
+    weighting: &PixelWeighting,
+    decoding_table: &[f32; 256],
+    accum_row: &mut [T],
+    frame_row: &[u8],
+    factor: f32,
+    blend: impl Fn(f32, f32, f32) -> f32,
+) {
+    if factor <= 0.0 {
+        return;
+    }
+    for (accum, pixel) in accum_row.chunks_exact_mut(4).zip(frame_row.chunks_exact(4)) {
+        let alpha = pixel[3] as f32 / 255.0;
+        // The frame weight times the pixel alpha acts as opacity of the sample, color keys
+        // can push it above 1 so their colors win
+        let opacity = factor * alpha * weighting.key_boost(pixel);
+        for (channel, value) in accum[..3].iter_mut().zip(pixel) {
+            *channel = T::from_f32(blend(channel.to_f32(), decoding_table[*value as usize], opacity));
+        }
+        // The resulting alpha is the most opaque sample
+        accum[3] = T::from_f32(accum[3].to_f32().max(alpha));
+    }
+}
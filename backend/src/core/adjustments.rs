@@ -0,0 +1,306 @@
+use image::Rgba32FImage;
+use imageproc::filter::gaussian_blur_f32;
+use rayon::prelude::*;
+
+use crate::core::color::{linear_to_srgb, srgb_to_linear};
+use crate::models::{Adjustment, CurvePoint, LumaCoefficients};
+
+/**
+
+Applies post-processing steps to a rendered image, in the given order.
+The image stays in linear light, alpha is never changed.
+# Arguments
+- `long_exposure_img`: The linear light render, it is modified in place.
+- `adjustments`: The steps of the request.
+
+ */
+pub fn apply_adjustments(long_exposure_img: &mut Rgba32FImage, adjustments: &[Adjustment]) {
+    for adjustment in adjustments {
+        adjustment.apply(long_exposure_img);
+    }
+}
+
+impl Adjustment {
+    pub fn apply(&self, img: &mut Rgba32FImage) {
+        match self {
+            Adjustment::Exposure { ev } => {
+                let scale = 2f32.powf(*ev);
+                map_pixels(img, |pixel| pixel.iter_mut().for_each(|channel| *channel *= scale));
+            }
+            Adjustment::Levels {
+                black_point,
+                white_point,
+                gamma,
+            } => {
+                let range = (white_point - black_point).max(f32::EPSILON);
+                let inverse_gamma = 1.0 / gamma.max(f32::EPSILON);
+                map_encoded(img, |value| {
+                    ((value - black_point) / range).clamp(0.0, 1.0).powf(inverse_gamma)
+                });
+            }
+            Adjustment::Curve { points } => {
+                let curve = ToneCurve::new(points);
+                map_encoded(img, |value| curve.evaluate(value));
+            }
+            Adjustment::Saturation { amount } => map_pixels(img, |pixel| {
+                let luminance = LumaCoefficients::Rec709.luma(pixel[0], pixel[1], pixel[2]);
+                for channel in pixel.iter_mut() {
+                    *channel = (luminance + (*channel - luminance) * amount).max(0.0);
+                }
+            }),
+            Adjustment::WhiteBalance { temperature, tint } => {
+                let gains = [
+                    2f32.powf(temperature / 2.0),
+                    2f32.powf(-tint / 2.0),
+                    2f32.powf(-temperature / 2.0),
+                ];
+                // Normalized so that gray keeps its luminance
+                let luminance = LumaCoefficients::Rec709.luma(gains[0], gains[1], gains[2]);
+                map_pixels(img, |pixel| {
+                    for (channel, gain) in pixel.iter_mut().zip(gains) {
+                        *channel *= gain / luminance;
+                    }
+                });
+            }
+            Adjustment::UnsharpMask {
+                radius,
+                amount,
+                threshold,
+            } => {
+                if *radius <= 0.0 || *amount == 0.0 {
+                    return;
+                }
+                let blurred = gaussian_blur_f32(img, *radius);
+                img.par_chunks_mut(4)
+                    .zip(blurred.as_raw().par_chunks(4))
+                    .for_each(|(pixel, blurred_pixel)| {
+                        for (channel, blurred_value) in pixel[..3].iter_mut().zip(blurred_pixel) {
+                            let difference = *channel - blurred_value;
+                            if difference.abs() > *threshold {
+                                *channel = (*channel + difference * amount).max(0.0);
+                            }
+                        }
+                    });
+            }
+            Adjustment::Vignette { amount, midpoint } => apply_vignette(img, *amount, *midpoint),
+        }
+    }
+}
+
+/// Runs `map` on the color channels of every pixel.
+fn map_pixels(img: &mut Rgba32FImage, map: impl Fn(&mut [f32]) + Sync) {
+    img.par_chunks_mut(4).for_each(|pixel| map(&mut pixel[..3]));
+}
+
+/// Runs `map` on the sRGB encoded color channels, values below zero are clipped.
+fn map_encoded(img: &mut Rgba32FImage, map: impl Fn(f32) -> f32 + Sync) {
+    map_pixels(img, |pixel| {
+        for channel in pixel.iter_mut() {
+            *channel = srgb_to_linear(map(linear_to_srgb(channel.max(0.0))).max(0.0));
+        }
+    });
+}
+
+fn apply_vignette(img: &mut Rgba32FImage, amount: f32, midpoint: f32) {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 || amount == 0.0 {
+        return;
+    }
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    let corner_distance = center_x.hypot(center_y);
+    let midpoint = midpoint.clamp(0.0, 1.0);
+
+    img.par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let dy = y as f32 + 0.5 - center_y;
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let distance = (x as f32 + 0.5 - center_x).hypot(dy) / corner_distance;
+                let t = ((distance - midpoint) / (1.0 - midpoint).max(f32::EPSILON)).clamp(0.0, 1.0);
+                let factor = (1.0 + amount * t * t * (3.0 - 2.0 * t)).max(0.0);
+                for channel in &mut pixel[..3] {
+                    *channel *= factor;
+                }
+            }
+        });
+}
+
+/// Monotone cubic interpolation through the points of a tone curve (Fritsch–Carlson), which
+/// never overshoots between two points. The curve runs through `(0,0)` and `(1,1)` unless
+/// points at these inputs are given.
+struct ToneCurve {
+    inputs: Vec<f32>,
+    outputs: Vec<f32>,
+    tangents: Vec<f32>,
+}
+
+impl ToneCurve {
+    fn new(points: &[CurvePoint]) -> Self {
+        let mut points: Vec<CurvePoint> = points
+            .iter()
+            .filter(|point| point.input.is_finite() && point.output.is_finite())
+            .copied()
+            .collect();
+        for end in [0.0, 1.0] {
+            if !points.iter().any(|point| point.input == end) {
+                points.push(CurvePoint { input: end, output: end });
+            }
+        }
+        points.sort_by(|a, b| a.input.total_cmp(&b.input));
+        points.dedup_by(|a, b| a.input == b.input);
+
+        let inputs: Vec<f32> = points.iter().map(|point| point.input).collect();
+        let outputs: Vec<f32> = points.iter().map(|point| point.output).collect();
+        let slopes: Vec<f32> = (0..points.len() - 1)
+            .map(|i| (outputs[i + 1] - outputs[i]) / (inputs[i + 1] - inputs[i]))
+            .collect();
+        let mut tangents: Vec<f32> = (0..points.len())
+            .map(|i| match i {
+                0 => slopes[0],
+                i if i == slopes.len() => slopes[i - 1],
+                i if slopes[i - 1] * slopes[i] <= 0.0 => 0.0,
+                i => (slopes[i - 1] + slopes[i]) / 2.0,
+            })
+            .collect();
+        for (i, slope) in slopes.iter().enumerate() {
+            if *slope == 0.0 {
+                tangents[i] = 0.0;
+                tangents[i + 1] = 0.0;
+                continue;
+            }
+            let (a, b) = (tangents[i] / slope, tangents[i + 1] / slope);
+            let length = a.hypot(b);
+            if length > 3.0 {
+                tangents[i] = 3.0 / length * a * slope;
+                tangents[i + 1] = 3.0 / length * b * slope;
+            }
+        }
+
+        ToneCurve {
+            inputs,
+            outputs,
+            tangents,
+        }
+    }
+
+    fn evaluate(&self, value: f32) -> f32 {
+        let last = self.inputs.len() - 1;
+        if value <= self.inputs[0] {
+            return self.outputs[0];
+        }
+        if value >= self.inputs[last] {
+            return self.outputs[last];
+        }
+        let i = self.inputs.partition_point(|input| *input <= value) - 1;
+        let h = self.inputs[i + 1] - self.inputs[i];
+        let t = (value - self.inputs[i]) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * self.outputs[i]
+            + (t3 - 2.0 * t2 + t) * h * self.tangents[i]
+            + (-2.0 * t3 + 3.0 * t2) * self.outputs[i + 1]
+            + (t3 - t2) * h * self.tangents[i + 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn tone_curve_runs_through_points_without_overshooting() {
+        let curve = ToneCurve::new(&[
+            CurvePoint { input: 0.25, output: 0.1 },
+            CurvePoint { input: 0.75, output: 0.9 },
+        ]);
+        for (input, output) in [(0.0, 0.0), (0.25, 0.1), (0.75, 0.9), (1.0, 1.0)] {
+            assert!((curve.evaluate(input) - output).abs() < 1e-6);
+        }
+        let samples: Vec<f32> = (0..=100).map(|i| curve.evaluate(i as f32 / 100.0)).collect();
+        assert!(samples.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn adjustments_keep_alpha_and_gray() {
+        let mut img: Rgba32FImage = ImageBuffer::from_pixel(3, 3, Rgba([0.2, 0.2, 0.2, 0.5]));
+        apply_adjustments(
+            &mut img,
+            &[
+                Adjustment::Exposure { ev: 1.0 },
+                Adjustment::Saturation { amount: 0.0 },
+                Adjustment::WhiteBalance { temperature: 0.0, tint: 0.0 },
+                Adjustment::Vignette { amount: -1.0, midpoint: 0.5 },
+            ],
+        );
+
+        let center = img.get_pixel(1, 1).0;
+        for channel in &center[..3] {
+            assert!((channel - 0.4).abs() < 1e-6);
+        }
+        assert_eq!(center[3], 0.5);
+        assert!(img.get_pixel(0, 0).0[0] < 0.4);
+    }
+
+    #[test]
+    fn levels_stretch_the_encoded_range_and_bend_the_midtones() {
+        let mut img: Rgba32FImage = ImageBuffer::from_fn(3, 1, |x, _| {
+            let linear = srgb_to_linear([0.2, 0.5, 0.8][x as usize]);
+            Rgba([linear, linear, linear, 1.0])
+        });
+        Adjustment::Levels {
+            black_point: 0.2,
+            white_point: 0.8,
+            gamma: 2.0,
+        }
+        .apply(&mut img);
+
+        for (pixel, expected) in img.pixels().zip([0.0, 0.5f32.sqrt(), 1.0]) {
+            assert!((linear_to_srgb(pixel.0[0]) - expected).abs() < 1e-5, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn white_balance_shifts_colors_but_keeps_luminance() {
+        let mut img: Rgba32FImage = ImageBuffer::from_pixel(1, 1, Rgba([0.2, 0.2, 0.2, 1.0]));
+        Adjustment::WhiteBalance {
+            temperature: 1.0,
+            tint: 0.5,
+        }
+        .apply(&mut img);
+
+        let [red, green, blue, alpha] = img.get_pixel(0, 0).0;
+        // One stop between red and blue, the tint pulls green down by a quarter stop
+        assert!((red / blue - 2.0).abs() < 1e-5);
+        assert!((green / blue - 2f32.powf(0.25)).abs() < 1e-5);
+        assert!((LumaCoefficients::Rec709.luma(red, green, blue) - 0.2).abs() < 1e-6);
+        assert_eq!(alpha, 1.0);
+    }
+
+    #[test]
+    fn unsharp_mask_steepens_edges_above_the_threshold() {
+        let edge = || -> Rgba32FImage {
+            ImageBuffer::from_fn(20, 1, |x, _| {
+                let value = if x < 10 { 0.2 } else { 0.6 };
+                Rgba([value, value, value, 1.0])
+            })
+        };
+        let unsharp_mask = |threshold| Adjustment::UnsharpMask {
+            radius: 1.0,
+            amount: 1.0,
+            threshold,
+        };
+
+        let mut sharpened = edge();
+        unsharp_mask(0.0).apply(&mut sharpened);
+        assert!(sharpened.get_pixel(9, 0).0[0] < 0.2);
+        assert!(sharpened.get_pixel(10, 0).0[0] > 0.6);
+        // Flat areas away from the edge stay as they are
+        assert!((sharpened.get_pixel(0, 0).0[0] - 0.2).abs() < 1e-6);
+        assert!((sharpened.get_pixel(19, 0).0[0] - 0.6).abs() < 1e-6);
+
+        let mut untouched = edge();
+        unsharp_mask(1.0).apply(&mut untouched);
+        assert_eq!(untouched, edge());
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use image::imageops::{self, FilterType};
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use imageproc::geometric_transformations::{warp, Interpolation, Projection};
+use rustfft::num_complex::Complex32;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::models::{AlignmentMethod, AlignmentSettings};
+
+/// Name of the file in the frames directory that caches the estimated transforms.
+/// It lives next to the frames so it is dropped together with them when a video is re-extracted.
+const ALIGNMENT_CACHE_FILE_NAME: &str = "alignment.json";
+
+/// Number of alignment settings whose transforms are kept in the cache file.
+const MAX_CACHED_ALIGNMENTS: usize = 4;
+
+/// Longer side of the downscaled grayscale images the transforms are estimated on.
+const ANALYSIS_SIZE: u32 = 512;
+
+/**
+
+Maps a point of the reference frame to the same scene point in another frame:
+the point is rotated by `rotation` radians around the image center and shifted by
+`(dx, dy)` pixels afterwards.
+
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct FrameTransform {
+    pub rotation: f32,
+    pub dx: f32,
+    pub dy: f32,
+}
+
+impl FrameTransform {
+    fn is_identity(&self) -> bool {
+        self.rotation == 0.0 && self.dx.abs() < 1e-3 && self.dy.abs() < 1e-3
+    }
+
+    /// Projection from the reference frame onto the frame with the given dimensions.
+    fn projection(&self, (width, height): (u32, u32)) -> Projection {
+        let (cx, cy) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+        Projection::translate(-cx, -cy)
+            .and_then(Projection::rotate(self.rotation))
+            .and_then(Projection::translate(cx + self.dx, cy + self.dy))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedAlignment {
+    settings: AlignmentSettings,
+    /// Dimensions of the frames the shifts were measured in.
+    dimensions: (u32, u32),
+    transforms: HashMap<usize, FrameTransform>,
+}
+
+/**
+
+Registers frames against a reference frame using phase correlation.
+The frames are downscaled to grayscale, windowed and compared in the frequency domain,
+the peak of the normalized cross-power spectrum is the shift between them.
+Rotations are found by trying small angles and keeping the one with the strongest peak.
+Transforms are estimated the first time a frame is aligned and reused afterwards.
+
+ */
+pub struct FrameAligner {
+    settings: AlignmentSettings,
+    dimensions: (u32, u32),
+    analysis_dimensions: (u32, u32),
+    /// Factor from frame to analysis coordinates.
+    analysis_scale: f32,
+    window: Vec<f32>,
+    ffts: Ffts,
+    reference_spectrum: Vec<Complex32>,
+    transforms: Mutex<HashMap<usize, FrameTransform>>,
+}
+
+struct Ffts {
+    row_forward: Arc<dyn Fft<f32>>,
+    row_inverse: Arc<dyn Fft<f32>>,
+    column_forward: Arc<dyn Fft<f32>>,
+    column_inverse: Arc<dyn Fft<f32>>,
+}
+
+impl FrameAligner {
+    /**
+
+    Prepares the alignment of frames against a reference frame.
+    # Arguments
+    - `settings`: The alignment settings with the reference frame resolved.
+    - `reference`: The decoded reference frame.
+    - `transforms`: Transforms estimated by earlier renders with the same settings.
+
+     */
+    pub fn new(
+        settings: AlignmentSettings,
+        reference: &RgbaImage,
+        transforms: HashMap<usize, FrameTransform>,
+    ) -> Self {
+        let (width, height) = reference.dimensions();
+        let analysis_scale = (ANALYSIS_SIZE as f32 / width.max(height) as f32).min(1.0);
+        let analysis_dimensions = (
+            ((width as f32 * analysis_scale).round() as u32).max(1),
+            ((height as f32 * analysis_scale).round() as u32).max(1),
+        );
+        let (analysis_width, analysis_height) = analysis_dimensions;
+
+        let mut planner = FftPlanner::new();
+        let ffts = Ffts {
+            row_forward: planner.plan_fft_forward(analysis_width as usize),
+            row_inverse: planner.plan_fft_inverse(analysis_width as usize),
+            column_forward: planner.plan_fft_forward(analysis_height as usize),
+            column_inverse: planner.plan_fft_inverse(analysis_height as usize),
+        };
+
+        let mut aligner = FrameAligner {
+            settings,
+            dimensions: (width, height),
+            analysis_dimensions,
+            analysis_scale,
+            window: hann_window(analysis_width, analysis_height),
+            ffts,
+            reference_spectrum: Vec::new(),
+            transforms: Mutex::new(transforms),
+        };
+        aligner.reference_spectrum = aligner.spectrum(&aligner.analysis_image(reference));
+        aligner
+    }
+
+    pub fn settings(&self) -> &AlignmentSettings {
+        &self.settings
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// Transforms of all frames aligned so far, including the ones passed to `new`.
+    pub fn transforms(&self) -> HashMap<usize, FrameTransform> {
+        self.transforms.lock().unwrap().clone()
+    }
+
+    /// Warps a frame onto the reference frame. Areas the frame does not cover become
+    /// transparent, so they do not contribute to the blend.
+    /// Frames with other dimensions than the reference are returned untouched.
+    pub fn align(&self, frame_number: usize, frame: RgbaImage) -> RgbaImage {
+        if frame.dimensions() != self.dimensions {
+            return frame;
+        }
+
+        let cached = self.transforms.lock().unwrap().get(&frame_number).copied();
+        let transform = match cached {
+            Some(transform) => transform,
+            None => {
+                let transform = if Some(frame_number) == self.settings.reference_frame {
+                    FrameTransform::default()
+                } else {
+                    self.estimate(&frame)
+                };
+                self.transforms.lock().unwrap().insert(frame_number, transform);
+                transform
+            }
+        };
+
+        if transform.is_identity() {
+            return frame;
+        }
+        // `warp` expects the mapping from the frame onto the output
+        let projection = transform.projection(self.dimensions).invert();
+        warp(&frame, &projection, Interpolation::Bilinear, Rgba([0, 0, 0, 0]))
+    }
+
+    fn estimate(&self, frame: &RgbaImage) -> FrameTransform {
+        let analysis_img = self.analysis_image(frame);
+        let angles = match self.settings.method {
+            AlignmentMethod::Translation => vec![0.0],
+            AlignmentMethod::TranslationRotation => self.rotation_candidates(),
+        };
+
+        let correlations: Vec<((f32, f32), f32)> = angles
+            .iter()
+            .map(|angle| self.phase_correlation(&rotate_about_center(&analysis_img, *angle)))
+            .collect();
+        let best = (0..angles.len())
+            .max_by(|a, b| correlations[*a].1.total_cmp(&correlations[*b].1))
+            .unwrap_or_default();
+        let (mut angle, (mut shift, _)) = (angles[best], correlations[best]);
+
+        // Refine the angle between the candidates with a parabola through the peak strengths
+        if best > 0 && best + 1 < angles.len() {
+            let (previous, peak, next) = (
+                correlations[best - 1].1,
+                correlations[best].1,
+                correlations[best + 1].1,
+            );
+            let denominator = previous - 2.0 * peak + next;
+            if denominator < 0.0 {
+                let offset = (0.5 * (previous - next) / denominator).clamp(-0.5, 0.5);
+                angle += offset * (angles[best + 1] - angles[best]);
+                shift = self.phase_correlation(&rotate_about_center(&analysis_img, angle)).0;
+            }
+        }
+
+        // The shift was measured on the rotated image, rotate it back into frame
+        // coordinates and scale it to full resolution
+        let (sin, cos) = angle.sin_cos();
+        FrameTransform {
+            rotation: angle,
+            dx: (cos * shift.0 - sin * shift.1) / self.analysis_scale,
+            dy: (sin * shift.0 + cos * shift.1) / self.analysis_scale,
+        }
+    }
+
+    /// Angles up to the maximum rotation, spaced so that the image corners move by about
+    /// one analysis pixel from one angle to the next.
+    fn rotation_candidates(&self) -> Vec<f32> {
+        let (width, height) = self.analysis_dimensions;
+        let step = 2.0 / (width as f32).hypot(height as f32);
+        let max_rotation = self.settings.max_rotation_degrees.abs().to_radians();
+        let steps = (max_rotation / step).ceil() as i32;
+        (-steps..=steps)
+            .map(|i| (i as f32 * step).clamp(-max_rotation, max_rotation))
+            .collect()
+    }
+
+    fn analysis_image(&self, frame: &RgbaImage) -> GrayImage {
+        let (width, height) = self.analysis_dimensions;
+        imageops::resize(&imageops::grayscale(frame), width, height, FilterType::Triangle)
+    }
+
+    /// Windowed 2D spectrum of an analysis image without its mean.
+    fn spectrum(&self, img: &GrayImage) -> Vec<Complex32> {
+        let mean = img.as_raw().iter().map(|value| *value as f32).sum::<f32>()
+            / img.as_raw().len() as f32;
+        let mut data: Vec<Complex32> = img
+            .as_raw()
+            .iter()
+            .zip(&self.window)
+            .map(|(value, window)| Complex32::new((*value as f32 - mean) * window, 0.0))
+            .collect();
+        self.fft_2d(&mut data, false);
+        data
+    }
+
+    /// Shift `d` such that `img(p) = reference(p - d)` together with the strength of the
+    /// correlation peak.
+    fn phase_correlation(&self, img: &GrayImage) -> ((f32, f32), f32) {
+        let mut cross_power: Vec<Complex32> = self
+            .spectrum(img)
+            .iter()
+            .zip(&self.reference_spectrum)
+            .map(|(frame, reference)| {
+                let product = frame * reference.conj();
+                product / (product.norm() + 1e-6)
+            })
+            .collect();
+        self.fft_2d(&mut cross_power, true);
+
+        let (width, height) = (
+            self.analysis_dimensions.0 as usize,
+            self.analysis_dimensions.1 as usize,
+        );
+        let correlation: Vec<f32> = cross_power.iter().map(|value| value.re).collect();
+        let (peak_index, peak) = correlation
+            .iter()
+            .copied()
+            .enumerate()
+            .fold((0, f32::MIN), |best, (i, value)| if value > best.1 { (i, value) } else { best });
+        let (peak_x, peak_y) = (peak_index % width, peak_index / width);
+
+        // Refine the peak to sub-pixel accuracy with a parabola through its neighbours
+        let at = |x: usize, y: usize| correlation[(y % height) * width + x % width];
+        let refine = |previous: f32, next: f32| {
+            let denominator = previous - 2.0 * peak + next;
+            if denominator.abs() > f32::EPSILON {
+                (0.5 * (previous - next) / denominator).clamp(-0.5, 0.5)
+            } else {
+                0.0
+            }
+        };
+        let offset_x = refine(at(peak_x + width - 1, peak_y), at(peak_x + 1, peak_y));
+        let offset_y = refine(at(peak_x, peak_y + height - 1), at(peak_x, peak_y + 1));
+
+        // The correlation is circular, peaks past the middle are negative shifts
+        let unwrap = |peak: usize, len: usize| {
+            if peak > len / 2 {
+                peak as f32 - len as f32
+            } else {
+                peak as f32
+            }
+        };
+        (
+            (
+                unwrap(peak_x, width) + offset_x,
+                unwrap(peak_y, height) + offset_y,
+            ),
+            peak,
+        )
+    }
+
+    fn fft_2d(&self, data: &mut [Complex32], inverse: bool) {
+        let (width, height) = (
+            self.analysis_dimensions.0 as usize,
+            self.analysis_dimensions.1 as usize,
+        );
+        let (row_fft, column_fft) = if inverse {
+            (&self.ffts.row_inverse, &self.ffts.column_inverse)
+        } else {
+            (&self.ffts.row_forward, &self.ffts.column_forward)
+        };
+
+        // A buffer of several rows is transformed row by row
+        row_fft.process(data);
+        let mut columns = vec![Complex32::default(); data.len()];
+        for (i, value) in data.iter().enumerate() {
+            columns[(i % width) * height + i / width] = *value;
+        }
+        column_fft.process(&mut columns);
+        for (i, value) in columns.iter().enumerate() {
+            data[(i % height) * width + i / height] = *value;
+        }
+    }
+}
+
+/// Samples `img` rotated by `angle` around its center, so that a point `p` of the result
+/// shows `img` at `p` rotated by `angle`. Uncovered areas are filled with the mean brightness.
+fn rotate_about_center(img: &GrayImage, angle: f32) -> GrayImage {
+    if angle == 0.0 {
+        return img.clone();
+    }
+    let mean = img.as_raw().iter().map(|value| *value as u32).sum::<u32>()
+        / img.as_raw().len().max(1) as u32;
+    let rotation = FrameTransform {
+        rotation: angle,
+        dx: 0.0,
+        dy: 0.0,
+    };
+    let projection = rotation.projection(img.dimensions()).invert();
+    warp(img, &projection, Interpolation::Bilinear, Luma([mean as u8]))
+}
+
+/// Separable Hann window, keeps the image borders from dominating the spectrum.
+fn hann_window(width: u32, height: u32) -> Vec<f32> {
+    let hann = |i: u32, len: u32| {
+        if len < 2 {
+            1.0
+        } else {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (len - 1) as f32).cos()
+        }
+    };
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| hann(x, width) * hann(y, height)))
+        .collect()
+}
+
+/// Transforms cached for the given alignment settings and frame dimensions in the frames directory.
+/// A missing or unreadable cache just means the transforms are estimated again.
+pub fn read_cached_transforms(
+    frames_dir_path: &Path,
+    settings: &AlignmentSettings,
+    dimensions: (u32, u32),
+) -> HashMap<usize, FrameTransform> {
+    read_alignment_cache(frames_dir_path)
+        .into_iter()
+        .find(|cached| cached.settings == *settings && cached.dimensions == dimensions)
+        .map(|cached| cached.transforms)
+        .unwrap_or_default()
+}
+
+/// Stores the transforms of the given alignment settings and frame dimensions in the frames
+/// directory, replacing the least recently stored entry once the cache is full.
+pub fn write_cached_transforms(
+    frames_dir_path: &Path,
+    settings: &AlignmentSettings,
+    dimensions: (u32, u32),
+    transforms: HashMap<usize, FrameTransform>,
+) {
+    let mut cache = read_alignment_cache(frames_dir_path);
+    cache.retain(|cached| cached.settings != *settings || cached.dimensions != dimensions);
+    cache.push(CachedAlignment {
+        settings: *settings,
+        dimensions,
+        transforms,
+    });
+    let excess = cache.len().saturating_sub(MAX_CACHED_ALIGNMENTS);
+    cache.drain(..excess);
+
+    let cache_path = frames_dir_path.join(ALIGNMENT_CACHE_FILE_NAME);
+    let result = serde_json::to_string(&cache)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(&cache_path, json).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("Failed to write alignment cache {:?}: {}", cache_path, e);
+    }
+}
+
+fn read_alignment_cache(frames_dir_path: &Path) -> Vec<CachedAlignment> {
+    let cache_path = frames_dir_path.join(ALIGNMENT_CACHE_FILE_NAME);
+    match fs::read_to_string(&cache_path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring invalid alignment cache {:?}: {}", cache_path, e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageBuffer;
+
+    use super::*;
+
+    /// Smooth noise with a broad spectrum like real footage, moved by `(dx, dy)` pixels.
+    fn shifted_pattern(dx: f32, dy: f32) -> RgbaImage {
+        transformed_pattern(FrameTransform {
+            rotation: 0.0,
+            dx,
+            dy,
+        })
+    }
+
+    /// The test pattern as seen in a frame that is transformed against the reference like
+    /// described by `transform`.
+    fn transformed_pattern(transform: FrameTransform) -> RgbaImage {
+        let (width, height) = (160, 120);
+        let (cx, cy) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+        let (sin, cos) = transform.rotation.sin_cos();
+        ImageBuffer::from_fn(width, height, |x, y| {
+            // Undo the shift and the rotation around the center to find the reference point
+            let (x, y) = (x as f32 - cx - transform.dx, y as f32 - cy - transform.dy);
+            let (x, y) = (cos * x + sin * y + cx, -sin * x + cos * y + cy);
+            let value = 127.0 + 50.0 * value_noise(x / 7.0, y / 7.0) + 30.0 * value_noise(x / 3.0, y / 3.0);
+            Rgba([value as u8, value as u8, value as u8, 255])
+        })
+    }
+
+    /// Smoothly interpolated pseudo random values in `[-1,1]` on an integer grid.
+    fn value_noise(x: f32, y: f32) -> f32 {
+        let lattice = |x: i32, y: i32| {
+            let hash = (x as u32).wrapping_mul(0x9E37_79B9) ^ (y as u32).wrapping_mul(0x85EB_CA6B);
+            let hash = (hash ^ (hash >> 15)).wrapping_mul(0x2C1B_3C6D);
+            (hash >> 8) as f32 / (1 << 23) as f32 - 1.0
+        };
+        let (x0, y0) = (x.floor(), y.floor());
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        let (tx, ty) = (smooth(x - x0), smooth(y - y0));
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let top = lattice(x0, y0) * (1.0 - tx) + lattice(x0 + 1, y0) * tx;
+        let bottom = lattice(x0, y0 + 1) * (1.0 - tx) + lattice(x0 + 1, y0 + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    #[test]
+    fn estimates_translation_against_reference() {
+        let settings = AlignmentSettings {
+            method: AlignmentMethod::Translation,
+            reference_frame: Some(0),
+            max_rotation_degrees: 0.0,
+        };
+        let aligner = FrameAligner::new(settings, &shifted_pattern(0.0, 0.0), HashMap::new());
+
+        let transform = aligner.estimate(&shifted_pattern(7.0, -4.0));
+        assert!((transform.dx - 7.0).abs() < 0.5, "{:?}", transform);
+        assert!((transform.dy + 4.0).abs() < 0.5, "{:?}", transform);
+
+        // The aligned frame matches the reference away from the uncovered border
+        let aligned = aligner.align(1, shifted_pattern(7.0, -4.0));
+        let reference = shifted_pattern(0.0, 0.0);
+        for (x, y) in [(40, 40), (80, 60), (120, 90)] {
+            let difference =
+                aligned.get_pixel(x, y)[0] as i32 - reference.get_pixel(x, y)[0] as i32;
+            assert!(difference.abs() <= 8, "{} at {:?}", difference, (x, y));
+        }
+        assert_eq!(aligned.get_pixel(159, 0)[3], 0);
+    }
+
+    #[test]
+    fn estimates_rotation_against_reference() {
+        let settings = AlignmentSettings {
+            method: AlignmentMethod::TranslationRotation,
+            reference_frame: Some(0),
+            max_rotation_degrees: 5.0,
+        };
+        let aligner = FrameAligner::new(settings, &shifted_pattern(0.0, 0.0), HashMap::new());
+        let expected = FrameTransform {
+            rotation: 3f32.to_radians(),
+            dx: 4.0,
+            dy: -2.0,
+        };
+
+        let transform = aligner.estimate(&transformed_pattern(expected));
+        assert!((transform.rotation - expected.rotation).abs() < 0.1f32.to_radians(), "{:?}", transform);
+        assert!((transform.dx - expected.dx).abs() < 0.5, "{:?}", transform);
+        assert!((transform.dy - expected.dy).abs() < 0.5, "{:?}", transform);
+
+        // The aligned frame matches the reference around the center
+        let aligned = aligner.align(1, transformed_pattern(expected));
+        let reference = shifted_pattern(0.0, 0.0);
+        for (x, y) in [(50, 40), (80, 60), (110, 80)] {
+            let difference =
+                aligned.get_pixel(x, y)[0] as i32 - reference.get_pixel(x, y)[0] as i32;
+            assert!(difference.abs() <= 8, "{} at {:?}", difference, (x, y));
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{Rgba32FImage, RgbaImage};
+use tokio::process::Command;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::core::accumulator::{frame_factors, Accumulator, AccumulatorValue, BlendSettings};
+use crate::core::long_exposure_image_logic::{
+    accumulate_frames, contributing_frames, finish_render, render_frames, stream_frames,
+    FinishingPlates, FrameSource,
+};
+use crate::core::output::{encode_rgba8, tone_map_and_resize};
+use crate::models::{Animation, AnimationFormat, AnimationSettings, CreateLongExposureImageRequest, FrameData};
+use crate::utils;
+
+/// Frame rate of animations when neither the request nor the project sets one.
+const DEFAULT_ANIMATION_FPS: u32 = 25;
+
+/// The FFmpeg executable animations are encoded with.
+const FFMPEG: &str = "ffmpeg";
+
+/// Directory the frames of one animation are written to. Every render gets its own, so concurrent
+/// renders do not touch each other's frames, and it is removed when dropped, also if the render
+/// or the encoding fails.
+struct AnimationFramesDir(PathBuf);
+
+impl AnimationFramesDir {
+    fn create(output_dir: &Path) -> Result<Self, String> {
+        let path = output_dir.join(format!("animation_frames_{}", Uuid::new_v4().simple()));
+        fs::create_dir_all(&path).map_err(|e| format!("Failed to create animation frames directory: {}", e))?;
+        Ok(AnimationFramesDir(path))
+    }
+}
+
+impl Drop for AnimationFramesDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            warn!("Failed to remove animation frames {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/**
+
+Renders the animation of a request and encodes it with FFmpeg. Every animation frame is finished
+like the still image (mask, frozen frame, rotation and adjustments) with the plates of the still
+image and tone mapped and resized with the output settings. The frames are written as PNGs into a
+temporary directory of this render next to the animation, which is removed once it is encoded or
+the render failed.
+# Arguments
+- `animation`: The kind, format and frame rate of the animation.
+- `request`: The render request with its frame selection already expanded.
+- `settings`: The blend mode, weighting and color space of the render.
+- `source`: The frames of the render.
+- `plates`: The key frame, mask and frozen frame the still image was finished with.
+- `output_dir`: The directory the animation is written to.
+# Returns
+- The path of the encoded animation, or an error message.
+
+ */
+pub async fn render_animation(
+    animation: &AnimationSettings,
+    request: &CreateLongExposureImageRequest,
+    settings: &BlendSettings,
+    source: &FrameSource,
+    plates: &FinishingPlates,
+    output_dir: &Path,
+) -> Result<PathBuf, String> {
+    let animation_frames_dir = AnimationFramesDir::create(output_dir)?;
+
+    let frames_data = contributing_frames(&request.frames_to_include)?;
+    let dimensions = source
+        .region_dimensions()
+        .ok_or_else(|| "No images were chosen".to_string())?;
+    let load_rendered = |frame_number| source.load_rendered(frame_number);
+    let write_frame = |index: usize, long_exposure_img: Rgba32FImage| {
+        let long_exposure_img = finish_render(long_exposure_img, request, plates);
+        encode_rgba8(tone_map_and_resize(long_exposure_img, &request.output), settings)
+            .save(animation_frame_path(&animation_frames_dir.0, index))
+            .map_err(|e| format!("Failed to save animation frame {}: {}", index, e))
+    };
+    match animation.kind {
+        Animation::Rolling { window, step } => {
+            render_rolling_window(&frames_data, settings, dimensions, window, step, load_rendered, write_frame)?
+        }
+        Animation::BuildUp { every } => render_build_up(&frames_data, settings, every, load_rendered, write_frame)?,
+    };
+
+    let animation_path =
+        utils::generate_timestamped_path(output_dir, "long_exposure_animation", animation.format.extension());
+    let fps = animation.fps.unwrap_or(DEFAULT_ANIMATION_FPS);
+    encode_animation(FFMPEG, &animation_frames_dir.0, fps, animation.format, &animation_path).await?;
+    Ok(animation_path)
+}
+
+/**
+
+Slides a window over the frames and blends the frames inside it for every position.
+For reversible blend modes the window is moved incrementally: only the frames that enter the
+window are added to the accumulator and the frames that leave it are decoded again and removed,
+so every frame is decoded at most twice regardless of the window size. The accumulator keeps `f64`
+values, so the rounding errors of the removals stay far below what an 8-bit frame can show.
+Other modes blend every window from scratch.
+# Arguments
+- `frames_data`: The contributing frames in the order they are played.
+- `settings`: The blend mode, weighting and color space of the render.
+- `dimensions`: The dimensions all frames share.
+- `window`: The number of frames blended into one animation frame, cut to the number of frames.
+- `step`: The number of frames the window moves between two animation frames.
+- `load_frame`: Decodes a frame by its frame number.
+- `emit`: Called with the index and the linear light image of every animation frame, in order.
+# Returns
+- The number of animation frames, or an error message.
+
+ */
+pub fn render_rolling_window<L, E>(
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    dimensions: (u32, u32),
+    window: usize,
+    step: usize,
+    load_frame: L,
+    mut emit: E,
+) -> Result<usize, String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+    E: FnMut(usize, Rgba32FImage) -> Result<(), String>,
+{
+    let window = window.clamp(1, frames_data.len().max(1));
+    let step = step.max(1);
+    let window_starts: Vec<usize> = (0..=frames_data.len().saturating_sub(window)).step_by(step).collect();
+
+    if !settings.blend_mode.is_reversible() {
+        for (index, start) in window_starts.iter().enumerate() {
+            let (long_exposure_img, _) =
+                render_frames(&frames_data[*start..start + window], settings, dimensions, &load_frame)?;
+            emit(index, long_exposure_img)?;
+        }
+        return Ok(window_starts.len());
+    }
+
+    let mut accumulator: Accumulator<f64> = accumulate_frames(&frames_data[..window], settings, &load_frame)?;
+    emit(0, accumulator.finish())?;
+    for (index, pair) in window_starts.windows(2).enumerate() {
+        let (start, next) = (pair[0], pair[1]);
+        // Windows do not overlap if the step is larger than the window
+        let leaving = &frames_data[start..next.min(start + window)];
+        let entering = &frames_data[next.max(start + window)..next + window];
+        let changes: Vec<(&FrameData, bool)> = leaving
+            .iter()
+            .map(|frame| (frame, false))
+            .chain(entering.iter().map(|frame| (frame, true)))
+            .collect();
+        let frame_numbers: Vec<usize> = changes.iter().map(|(frame, _)| frame.frame_number).collect();
+        // Reversible modes accumulate frames with their raw weight as factor
+        stream_frames(&frame_numbers, accumulator.dimensions(), &load_frame, |change, img| {
+            let (frame, entering) = changes[change];
+            if entering {
+                accumulator.add_frame(img, frame.frame_weight);
+            } else {
+                accumulator.remove_frame(img, frame.frame_weight);
+            }
+        })?;
+        emit(index + 1, accumulator.finish())?;
+    }
+    Ok(window_starts.len())
+}
+
+/**
+
+Adds the frames one after another and emits the blend of the frames so far every `every` frames
+and after the last one. A single accumulator is kept, the factors of all frames are computed from
+the whole selection, so the last animation frame matches the still image. Modes that are not
+streamable would have to blend every animation frame from scratch, which grows quadratically with
+the number of frames, so they are rejected.
+# Arguments
+- `frames_data`: The contributing frames in the order they are added.
+- `settings`: The blend mode, weighting and color space of the render.
+- `every`: The number of frames added between two animation frames.
+- `load_frame`: Decodes a frame by its frame number.
+- `emit`: Called with the index and the linear light image of every animation frame, in order.
+# Returns
+- The number of animation frames, or an error message if the blend mode is not streamable.
+
+ */
+pub fn render_build_up<L, E>(
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    every: usize,
+    load_frame: L,
+    emit: E,
+) -> Result<usize, String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+    E: FnMut(usize, Rgba32FImage) -> Result<(), String>,
+{
+    let every = every.max(1);
+    let frame_counts: Vec<usize> = (1..=frames_data.len())
+        .filter(|count| count % every == 0 || *count == frames_data.len())
+        .collect();
+
+    if !settings.blend_mode.is_streamable() {
+        return Err(format!("Build-up animations cannot be rendered with {:?}", settings.blend_mode));
+    }
+    if settings.blend_mode.is_reversible() {
+        build_up_accumulator::<f64, _, _>(frames_data, settings, &frame_counts, load_frame, emit)?;
+    } else {
+        build_up_accumulator::<f32, _, _>(frames_data, settings, &frame_counts, load_frame, emit)?;
+    }
+    Ok(frame_counts.len())
+}
+
+fn build_up_accumulator<T, L, E>(
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    frame_counts: &[usize],
+    load_frame: L,
+    mut emit: E,
+) -> Result<(), String>
+where
+    T: AccumulatorValue,
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+    E: FnMut(usize, Rgba32FImage) -> Result<(), String>,
+{
+    let factors = frame_factors(settings.blend_mode, frames_data);
+    let first_frame = load_frame(frames_data[0].frame_number)?;
+    let (width, height) = first_frame.dimensions();
+    let mut accumulator: Accumulator<T> = Accumulator::new(width, height, settings.clone());
+    accumulator.add_frame(&first_frame, factors[0]);
+    drop(first_frame);
+
+    let mut added = 1;
+    for (index, count) in frame_counts.iter().enumerate() {
+        let frame_numbers: Vec<usize> = frames_data[added..*count]
+            .iter()
+            .map(|frame| frame.frame_number)
+            .collect();
+        stream_frames(&frame_numbers, (width, height), &load_frame, |offset, img| {
+            accumulator.add_frame(img, factors[added + offset]);
+        })?;
+        added = *count;
+        emit(index, accumulator.finish())?;
+    }
+    Ok(())
+}
+
+fn animation_frame_path(animation_frames_dir: &Path, index: usize) -> PathBuf {
+    animation_frames_dir.join(format!("frame_{:05}.png", index))
+}
+
+impl AnimationFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AnimationFormat::Mp4 => "mp4",
+            AnimationFormat::Webm => "webm",
+            AnimationFormat::Gif => "gif",
+        }
+    }
+
+    /// FFmpeg arguments of the encoder. Videos are padded to even dimensions, as the chroma
+    /// subsampling of `yuv420p` requires them, GIFs get a palette computed from all frames.
+    fn encoder_args(&self) -> &'static [&'static str] {
+        match self {
+            AnimationFormat::Mp4 => &[
+                "-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+                "-c:v", "libx264",
+                "-pix_fmt", "yuv420p",
+                "-crf", "18",
+                "-movflags", "+faststart",
+            ],
+            AnimationFormat::Webm => &[
+                "-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+                "-c:v", "libvpx-vp9",
+                "-pix_fmt", "yuv420p",
+                "-crf", "30",
+                "-b:v", "0",
+            ],
+            AnimationFormat::Gif => &["-vf", "split[frames][copy];[copy]palettegen[palette];[frames][palette]paletteuse"],
+        }
+    }
+}
+
+/// Encodes the numbered PNG frames of a directory into an animation with the given FFmpeg executable.
+async fn encode_animation(
+    ffmpeg: &str,
+    animation_frames_dir: &Path,
+    fps: u32,
+    format: AnimationFormat,
+    animation_path: &Path,
+) -> Result<(), String> {
+    let input_path = animation_frames_dir.join("frame_%05d.png");
+    let output = Command::new(ffmpeg)
+        .args(["-y", "-framerate", &fps.max(1).to_string(), "-i"])
+        .arg(&input_path)
+        .args(format.encoder_args())
+        .arg(animation_path)
+        .output()
+        .await
+        .map_err(|e| format!("There was an error calling FFMPEG {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "FFMPEG failed to encode the animation: {}",
+            String::from_utf8_lossy(&output.stderr).lines().last().unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+    use crate::core::test_support::{frames_data, linear_settings, load_from};
+    use crate::models::BlendMode;
+
+    /// Writes numbered animation frames of a few pixels into a new frames directory.
+    fn write_animation_frames(test_name: &str, count: usize) -> AnimationFramesDir {
+        let output_dir = std::env::temp_dir().join(format!("{}_test_{}", test_name, std::process::id()));
+        let frames_dir = AnimationFramesDir::create(&output_dir).unwrap();
+        for index in 0..count {
+            let frame: RgbaImage = ImageBuffer::from_pixel(4, 3, Rgba([(index * 60) as u8, 0, 0, 255]));
+            frame.save(animation_frame_path(&frames_dir.0, index)).unwrap();
+        }
+        frames_dir
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs FFmpeg, run with `cargo test -- --ignored`"]
+    async fn encodes_numbered_frames_into_a_gif() {
+        let frames_dir = write_animation_frames("encode_animation", 3);
+        let animation_path = frames_dir.0.with_extension("gif");
+
+        encode_animation(FFMPEG, &frames_dir.0, 10, AnimationFormat::Gif, &animation_path).await.unwrap();
+        let gif = image::open(&animation_path).unwrap();
+        let output_dir = animation_path.parent().unwrap().to_path_buf();
+        drop(frames_dir);
+        fs::remove_dir_all(output_dir).unwrap();
+        assert_eq!((gif.width(), gif.height()), (4, 3));
+    }
+
+    #[actix_web::test]
+    async fn missing_ffmpeg_fails_and_frames_are_removed() {
+        let frames_dir = write_animation_frames("missing_ffmpeg", 2);
+        let frames_path = frames_dir.0.clone();
+        let animation_path = frames_path.with_extension("mp4");
+
+        let result = encode_animation(
+            "ffmpeg-that-does-not-exist",
+            &frames_path,
+            10,
+            AnimationFormat::Mp4,
+            &animation_path,
+        )
+        .await;
+        assert!(result.unwrap_err().contains("There was an error calling FFMPEG"));
+        drop(frames_dir);
+        assert!(!frames_path.exists());
+        assert!(!animation_path.exists());
+        fs::remove_dir(frames_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rolling_window_matches_blending_each_window() {
+        let frames: Vec<RgbaImage> = (0..7u32)
+            .map(|frame_number| {
+                ImageBuffer::from_fn(3, 2, |x, y| {
+                    let value = ((frame_number * 37 + x * 11 + y * 53) % 256) as u8;
+                    Rgba([value, 255 - value, value / 2, 255])
+                })
+            })
+            .collect();
+        let frames_data = frames_data((0..frames.len()).map(|frame_number| 1.0 + frame_number as f32 / 2.0));
+        let load = load_from(&frames);
+
+        for (blend_mode, step) in [(BlendMode::WeightedMean, 1), (BlendMode::WeightedMean, 4), (BlendMode::Lighten, 2)] {
+            let settings = linear_settings(blend_mode);
+            let mut rendered = Vec::new();
+            let count = render_rolling_window(&frames_data, &settings, (3, 2), 3, step, load, |index, img| {
+                rendered.push((index, img));
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(count, (frames.len() - 3) / step + 1);
+
+            for (index, img) in rendered {
+                let window = &frames_data[index * step..index * step + 3];
+                let (expected, _) = render_frames(window, &settings, (3, 2), load).unwrap();
+                for (value, expected) in img.as_raw().iter().zip(expected.as_raw()) {
+                    assert!((value - expected).abs() < 1e-5, "{:?} step {}", blend_mode, step);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn build_up_ends_with_the_still_image() {
+        let frames: Vec<RgbaImage> = (0..5u32)
+            .map(|frame_number| ImageBuffer::from_pixel(2, 2, Rgba([(frame_number * 50) as u8, 90, 200, 255])))
+            .collect();
+        let frames_data = frames_data(vec![1.0; frames.len()]);
+        let load = load_from(&frames);
+
+        for blend_mode in [BlendMode::WeightedMean, BlendMode::Lighten] {
+            let settings = linear_settings(blend_mode);
+            let mut rendered = Vec::new();
+            let count = render_build_up(&frames_data, &settings, 2, load, |_, img| {
+                rendered.push(img);
+                Ok(())
+            })
+            .unwrap();
+            // After two, four and all five frames
+            assert_eq!(count, 3);
+
+            let (still, _) = render_frames(&frames_data, &settings, (2, 2), load).unwrap();
+            let last = rendered.last().unwrap();
+            for (value, expected) in last.as_raw().iter().zip(still.as_raw()) {
+                assert!((value - expected).abs() < 1e-5, "{:?}", blend_mode);
+            }
+            assert_ne!(rendered[0].as_raw(), last.as_raw(), "{:?}", blend_mode);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use image::{ImageBuffer, Rgba32FImage, RgbaImage};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::core::accumulator::{frame_factors, Accumulator, AccumulatorValue, BlendSettings};
+use crate::core::contact_sheet::{contact_sheet_thumbnail, save_contact_sheet};
+use crate::core::long_exposure_image_logic::{
+    contributing_frames, finish_render, frames_read_by, prepare_request, prune_output_dir,
+    rejection_statistics, stream_frames, FinishingPlates, FramePreparation, FrameSource,
+};
+use crate::core::output::{save_long_exposure_image, write_render_record};
+use crate::core::sample_stack::{stack_band, stack_pixel_fn, StackPixelFn, SAMPLE_STACK_BUDGET_BYTES};
+use crate::core::stroboscopic::composite_stroboscopic;
+use crate::core::time_slice::TimeSlice;
+use crate::error::RenderError;
+use crate::models::{
+    BlendColorSpace, BlendMode, CreateLongExposureImageRequest, CreateLongExposureImageVariantsRequest,
+    CreateLongExposureImageVariantsResponse, FrameData, RejectionStatistics, RenderVariant,
+    VariantResponse,
+};
+use crate::utils::convert_image_path_to_serving_url;
+
+/// Most variants rendered at once, each reversible variant holds an `Accumulator<f64>` of the
+/// whole image while the frames are decoded.
+pub const MAX_VARIANTS: usize = 8;
+
+/// How long the images of a batch stay in the `variants` directory of a project, long enough for
+/// the client to compare and download them.
+pub const VARIANTS_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/**
+
+Renders several variants of a request in one pass over the frames, e.g. to compare blend modes
+or weightings. Every variant is the base request with its changes merged in. All variants have
+to prepare the frames the same way (preview, dimension policy, alignment and crop), as the frames
+are decoded and prepared in one shared pass and added to the blend of each variant that uses them,
+so every frame is decoded once. Modes that need all samples of a pixel keep the frames they use
+during the pass, the batch is rejected if these do not fit into `SAMPLE_STACK_BUDGET_BYTES`.
+Animations and tiles cannot be requested, accumulation states are not used.
+The images, each with its render record, are written to a directory of their own in the `variants`
+directory of the project, batches older than `VARIANTS_RETENTION` are removed from it.
+# Arguments
+- `frames_dir_path`: The directory where the frames are located.
+- `request`: The base request, the variants and whether a contact sheet is rendered.
+- `project_color_space`: The blend color space of the project, used if a variant does not set one.
+# Returns
+- The urls of the variant images in the order of the request and of the contact sheet, or the
+  error on failure.
+
+ */
+pub async fn create_long_exposure_image_variants(
+    frames_dir_path: PathBuf,
+    request: CreateLongExposureImageVariantsRequest,
+    project_color_space: BlendColorSpace,
+) -> Result<CreateLongExposureImageVariantsResponse, RenderError> {
+    if request.variants.is_empty() {
+        return Err(RenderError::InvalidRequest("No variants were requested".to_string()));
+    }
+    if request.variants.len() > MAX_VARIANTS {
+        return Err(RenderError::InvalidRequest(format!(
+            "At most {} variants can be rendered at once",
+            MAX_VARIANTS
+        )));
+    }
+    check_single_image("The base request", &request.base)?;
+    let base = serde_json::to_value(&request.base)
+        .map_err(|e| format!("Failed to serialize base request: {}", e))?;
+    let variants = request
+        .variants
+        .iter()
+        .enumerate()
+        .map(|(index, variant)| prepare_variant(&frames_dir_path, &base, index, variant, project_color_space))
+        .collect::<Result<Vec<Variant>, RenderError>>()?;
+
+    let preparation = &variants[0].preparation;
+    if let Some(variant) = variants.iter().find(|variant| variant.preparation != *preparation) {
+        return Err(RenderError::InvalidRequest(format!(
+            "Variant '{}' prepares the frames differently than the first one, preview, dimension policy, \
+             alignment and crop have to be the same for all variants",
+            variant.label
+        )));
+    }
+    let frame_numbers = unique_frame_numbers(
+        variants
+            .iter()
+            .flat_map(|variant| frames_read_by(&variant.request, &variant.preparation)),
+    );
+    let source = FrameSource::new(&frames_dir_path, frame_numbers, preparation)?;
+    let (width, height) = source
+        .region_dimensions()
+        .ok_or_else(|| RenderError::InvalidRequest("No images were chosen".to_string()))?;
+    let stack_frames = stacked_frame_numbers(&variants);
+    if stack_frames.len() * width as usize * height as usize * 4 > SAMPLE_STACK_BUDGET_BYTES {
+        return Err(RenderError::InvalidRequest(format!(
+            "The {} frames of the median, sigma_clipping and stroboscopic variants do not fit into memory \
+             at once, render these variants one at a time",
+            stack_frames.len()
+        )));
+    }
+    let rendered = render_variants(&variants, &source)?;
+
+    let batches_dir_path = frames_dir_path.join("..").join("variants");
+    prune_output_dir(&batches_dir_path, VARIANTS_RETENTION)?;
+    // Concurrent batches of a project must not overwrite each other
+    let variants_dir_path = batches_dir_path.join(Uuid::new_v4().simple().to_string());
+    std::fs::create_dir(&variants_dir_path).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let mut variant_responses = Vec::with_capacity(variants.len());
+    let mut thumbnails: Vec<(String, RgbaImage)> = Vec::new();
+    for (index, (variant, (long_exposure_img, rejection))) in variants.iter().zip(rendered).enumerate() {
+        let plates = FinishingPlates::load(&variant.request, &source, long_exposure_img.dimensions())?;
+        let long_exposure_img = finish_render(long_exposure_img, &variant.request, &plates);
+        if request.contact_sheet {
+            let thumbnail = contact_sheet_thumbnail(&long_exposure_img, &variant.request.output);
+            thumbnails.push((variant.label.clone(), thumbnail));
+        }
+        let image_path = save_long_exposure_image(
+            long_exposure_img,
+            &variant.request.output,
+            &variant.settings,
+            &variants_dir_path,
+            &format!("variant_{}", index + 1),
+        )?;
+        write_render_record(&image_path, &variant.record)?;
+        variant_responses.push(VariantResponse {
+            label: variant.label.clone(),
+            image_url: convert_image_path_to_serving_url(&image_path).await,
+            rejection,
+        });
+    }
+    source.store_alignment();
+
+    let contact_sheet_url = if request.contact_sheet {
+        let contact_sheet_path = save_contact_sheet(&thumbnails, &variants_dir_path)?;
+        Some(convert_image_path_to_serving_url(&contact_sheet_path).await)
+    } else {
+        None
+    };
+    Ok(CreateLongExposureImageVariantsResponse {
+        variants: variant_responses,
+        contact_sheet_url,
+    })
+}
+
+/// A variant with its frame selection expanded and its settings resolved.
+struct Variant {
+    label: String,
+    request: CreateLongExposureImageRequest,
+    record: CreateLongExposureImageRequest,
+    settings: BlendSettings,
+    preparation: FramePreparation,
+    /// The frames that contribute to the blend, see `contributing_frames`.
+    frames_data: Vec<FrameData>,
+}
+
+fn prepare_variant(
+    frames_dir_path: &Path,
+    base: &Value,
+    index: usize,
+    variant: &RenderVariant,
+    project_color_space: BlendColorSpace,
+) -> Result<Variant, RenderError> {
+    let label = variant
+        .label
+        .clone()
+        .unwrap_or_else(|| format!("Variant {}", index + 1));
+    let mut merged = base.clone();
+    merge_patch(&mut merged, &Value::Object(variant.changes.clone()));
+    let mut request: CreateLongExposureImageRequest = serde_json::from_value(merged).map_err(|e| {
+        RenderError::InvalidRequest(format!("Variant '{}' is not a valid render request: {}", label, e))
+    })?;
+    check_single_image(&format!("Variant '{}'", label), &request)?;
+    let (record, settings, preparation) = prepare_request(frames_dir_path, &mut request, project_color_space)?;
+    let frames_data = contributing_frames(&request.frames_to_include)?;
+    Ok(Variant {
+        label,
+        request,
+        record,
+        settings,
+        preparation,
+        frames_data,
+    })
+}
+
+/// Variants are single images, animations and tiles are rejected instead of being left out.
+fn check_single_image(name: &str, request: &CreateLongExposureImageRequest) -> Result<(), RenderError> {
+    if request.animation.is_some() || request.tile_rows.is_some() {
+        return Err(RenderError::InvalidRequest(format!(
+            "{} cannot set animation or tile_rows, variants are rendered as single images",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Merges `patch` into `target` as described by RFC 7396 (JSON Merge Patch).
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch_fields) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    let Value::Object(target_fields) = target else {
+        return;
+    };
+    for (key, value) in patch_fields {
+        if value.is_null() {
+            target_fields.remove(key);
+        } else {
+            merge_patch(target_fields.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Frame numbers in the order they first appear, without duplicates.
+fn unique_frame_numbers(frame_numbers: impl Iterator<Item = usize>) -> Vec<usize> {
+    let mut seen = HashSet::new();
+    frame_numbers.filter(|frame_number| seen.insert(*frame_number)).collect()
+}
+
+/// How a variant blends the frames while they are decoded.
+enum VariantBlend {
+    Reversible(Accumulator<f64>, Vec<(usize, f32)>),
+    Streamed(Accumulator<f32>, Vec<(usize, f32)>),
+    /// Stacked from the kept frames after the shared pass.
+    Stacked(SampleStack),
+    TimeSlice(TimeSlice),
+}
+
+/// A variant whose blend mode needs all samples of a pixel at once.
+struct SampleStack {
+    image: Rgba32FImage,
+    rejected_samples: usize,
+    /// Index into the shared band buffer and weight of every frame of the variant.
+    slots: Vec<(usize, f32)>,
+    pixel_fn: StackPixelFn,
+    color_space: BlendColorSpace,
+}
+
+/// Whether the blend mode needs all samples of a pixel at once.
+fn is_stacked(blend_mode: BlendMode) -> bool {
+    !blend_mode.is_streamable() && !matches!(blend_mode, BlendMode::TimeSlice { .. })
+}
+
+/// The frames kept during the shared pass for the variants that need all samples of a pixel.
+fn stacked_frame_numbers(variants: &[Variant]) -> Vec<usize> {
+    unique_frame_numbers(
+        variants
+            .iter()
+            .filter(|variant| is_stacked(variant.settings.blend_mode))
+            .flat_map(|variant| variant.frames_data.iter().map(|frame| frame.frame_number)),
+    )
+}
+
+/// Adds a frame with every factor the variant gives it, a frame can be selected more than once.
+fn add_matching_frames<T: AccumulatorValue>(
+    accumulator: &mut Accumulator<T>,
+    factors: &[(usize, f32)],
+    frame_number: usize,
+    img: &RgbaImage,
+) {
+    for (_, factor) in factors.iter().filter(|(number, _)| *number == frame_number) {
+        accumulator.add_frame(img, *factor);
+    }
+}
+
+/// Blends the frames of all variants in one shared pass, the frames of stacked modes are kept until
+/// the end of the pass and must fit into `SAMPLE_STACK_BUDGET_BYTES`. Returns the linear light image
+/// and the rejection statistics of every variant in order.
+fn render_variants(
+    variants: &[Variant],
+    source: &FrameSource,
+) -> Result<Vec<(Rgba32FImage, Option<RejectionStatistics>)>, String> {
+    let (width, height) = source
+        .region_dimensions()
+        .ok_or_else(|| "No images were chosen".to_string())?;
+    let frame_numbers = unique_frame_numbers(
+        variants
+            .iter()
+            .flat_map(|variant| variant.frames_data.iter().map(|frame| frame.frame_number)),
+    );
+    let stack_frames = stacked_frame_numbers(variants);
+
+    let mut blends: Vec<VariantBlend> = variants
+        .iter()
+        .map(|variant| {
+            let blend_mode = variant.settings.blend_mode;
+            let factors = variant
+                .frames_data
+                .iter()
+                .map(|frame| frame.frame_number)
+                .zip(frame_factors(blend_mode, &variant.frames_data))
+                .collect();
+            if blend_mode.is_reversible() {
+                VariantBlend::Reversible(Accumulator::new(width, height, variant.settings.clone()), factors)
+            } else if let BlendMode::TimeSlice { layout, blend } = blend_mode {
+                VariantBlend::TimeSlice(TimeSlice::new(
+                    &variant.frames_data,
+                    layout,
+                    blend,
+                    variant.settings.color_space,
+                    (width, height),
+                    0..height,
+                ))
+            } else if blend_mode.is_streamable() {
+                VariantBlend::Streamed(Accumulator::new(width, height, variant.settings.clone()), factors)
+            } else {
+                let (pixel_fn, color_space) = stack_pixel_fn(&variant.settings);
+                let slots = variant
+                    .frames_data
+                    .iter()
+                    .map(|frame| {
+                        let slot = stack_frames.iter().position(|number| *number == frame.frame_number);
+                        (slot.unwrap_or_default(), frame.frame_weight)
+                    })
+                    .collect();
+                VariantBlend::Stacked(SampleStack {
+                    image: ImageBuffer::new(width, height),
+                    rejected_samples: 0,
+                    slots,
+                    pixel_fn,
+                    color_space,
+                })
+            }
+        })
+        .collect();
+
+    let frame_bytes = width as usize * height as usize * 4;
+    let mut kept_frames = vec![0u8; frame_bytes * stack_frames.len()];
+    let load_rendered = |frame_number| source.load_rendered(frame_number);
+    stream_frames(&frame_numbers, (width, height), load_rendered, |index, img| {
+        let frame_number = frame_numbers[index];
+        for (variant, blend) in variants.iter().zip(&mut blends) {
+            match blend {
+                VariantBlend::Reversible(accumulator, factors) => {
+                    add_matching_frames(accumulator, factors, frame_number, img)
+                }
+                VariantBlend::Streamed(accumulator, factors) => {
+                    add_matching_frames(accumulator, factors, frame_number, img)
+                }
+                VariantBlend::TimeSlice(time_slice) => {
+                    for (slice, frame) in variant.frames_data.iter().enumerate() {
+                        if frame.frame_number == frame_number {
+                            time_slice.add_frame(slice, img);
+                        }
+                    }
+                }
+                VariantBlend::Stacked(_) => {}
+            }
+        }
+        if let Some(slot) = stack_frames.iter().position(|number| *number == frame_number) {
+            kept_frames[slot * frame_bytes..(slot + 1) * frame_bytes].copy_from_slice(img.as_raw());
+        }
+    })?;
+    let load_kept = |frame_number| {
+        let slot = stack_frames
+            .iter()
+            .position(|number| *number == frame_number)
+            .ok_or_else(|| format!("Frame {} was not kept for stacking", frame_number))?;
+        RgbaImage::from_raw(width, height, kept_frames[slot * frame_bytes..(slot + 1) * frame_bytes].to_vec())
+            .ok_or_else(|| format!("Frame {} was not kept completely", frame_number))
+    };
+
+    let mut rendered = Vec::with_capacity(variants.len());
+    for (variant, blend) in variants.iter().zip(blends) {
+        rendered.push(match blend {
+            VariantBlend::Reversible(accumulator, _) => (accumulator.finish(), None),
+            VariantBlend::Streamed(accumulator, _) => (accumulator.finish(), None),
+            VariantBlend::TimeSlice(time_slice) => (time_slice.finish(), None),
+            VariantBlend::Stacked(mut stack) => {
+                stack.rejected_samples +=
+                    stack_band(&kept_frames, frame_bytes, &stack.slots, stack.image.as_mut(), &stack.pixel_fn);
+                stack.color_space.convert_to_linear(&mut stack.image);
+                composite_stroboscopic(&mut stack.image, variant.settings.blend_mode, &variant.frames_data, load_kept)?;
+                let rejection = rejection_statistics(
+                    variant.settings.blend_mode,
+                    stack.rejected_samples,
+                    variant.frames_data.len(),
+                    (width, height),
+                );
+                (stack.image, rejection)
+            }
+        });
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use serde_json::json;
+
+    use super::*;
+    use crate::core::long_exposure_image_logic::{accumulate_frames, render_frames};
+    use crate::core::test_support::load_from;
+
+    #[test]
+    fn merge_patch_replaces_merges_and_removes_fields() {
+        let mut target = json!({"blend_mode": "lighten", "output": {"format": {"type": "png"}, "max_dimension": 800}});
+        merge_patch(
+            &mut target,
+            &json!({"blend_mode": {"sigma_clipping": {"kappa": 2.0}}, "output": {"max_dimension": null}}),
+        );
+        assert_eq!(
+            target,
+            json!({"blend_mode": {"sigma_clipping": {"kappa": 2.0}}, "output": {"format": {"type": "png"}}})
+        );
+    }
+
+    #[test]
+    fn variants_match_single_renders() {
+        let frames_dir_path = std::env::temp_dir().join(format!("batch_test_{}", std::process::id()));
+        std::fs::create_dir_all(&frames_dir_path).unwrap();
+        let frames: Vec<RgbaImage> = (0..4u8)
+            .map(|frame_number| {
+                ImageBuffer::from_fn(6, 5, |x, y| {
+                    let value = (frame_number as u32 * 61 + x * 23 + y * 41) % 256;
+                    Rgba([value as u8, 255 - value as u8, (value / 2) as u8, 255])
+                })
+            })
+            .collect();
+        for (frame_number, frame) in frames.iter().enumerate() {
+            frame.save(frames_dir_path.join(format!("ffout_{:04}.png", frame_number))).unwrap();
+        }
+
+        let base = json!({"frames_to_include": (0..4)
+            .map(|frame_number| json!({"frame_number": frame_number, "frame_weight": 1.0 + frame_number as f32}))
+            .collect::<Vec<_>>()});
+        let variants: Vec<Variant> = [
+            json!({}),
+            json!({"blend_mode": "median"}),
+            json!({"blend_mode": "lighten"}),
+            json!({"blend_mode": {"time_slice": {"layout": {"type": "horizontal"}, "blend": 0.5}}}),
+            json!({"blend_mode": {"stroboscopic": {"every": 2, "feather": 1.0}}}),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, changes)| {
+            let variant: RenderVariant = serde_json::from_value(changes).unwrap();
+            prepare_variant(&frames_dir_path, &base, index, &variant, BlendColorSpace::Linear).unwrap()
+        })
+        .collect();
+        let source = FrameSource::new(&frames_dir_path, vec![0, 1, 2, 3], &variants[0].preparation).unwrap();
+        let rendered = render_variants(&variants, &source).unwrap();
+        std::fs::remove_dir_all(&frames_dir_path).unwrap();
+
+        for (variant, (long_exposure_img, _)) in variants.iter().zip(rendered) {
+            let load = load_from(&frames);
+            let (expected, _) = if variant.settings.blend_mode.is_reversible() {
+                let accumulator: Accumulator<f64> =
+                    accumulate_frames(&variant.frames_data, &variant.settings, load).unwrap();
+                (accumulator.finish(), None)
+            } else {
+                render_frames(&variant.frames_data, &variant.settings, (6, 5), load).unwrap()
+            };
+            assert_eq!(long_exposure_img.as_raw(), expected.as_raw(), "{}", variant.label);
+        }
+    }
+
+    #[actix_web::test]
+    async fn animations_tiles_and_too_many_variants_are_rejected() {
+        let frames_dir_path = std::env::temp_dir().join("no_project").join("frames");
+        let frames_to_include = json!([{"frame_number": 1, "frame_weight": 1.0}]);
+        let requests = [
+            json!({"base": {"frames_to_include": frames_to_include, "tile_rows": 64}, "variants": [{}]}),
+            json!({
+                "base": {"frames_to_include": frames_to_include},
+                "variants": [{"animation": {"type": "build_up"}}],
+            }),
+            json!({"base": {"frames_to_include": frames_to_include}, "variants": vec![json!({}); MAX_VARIANTS + 1]}),
+        ];
+        for request in requests {
+            let request: CreateLongExposureImageVariantsRequest = serde_json::from_value(request).unwrap();
+            let rendered =
+                create_long_exposure_image_variants(frames_dir_path.clone(), request, BlendColorSpace::Linear).await;
+            assert!(matches!(rendered, Err(RenderError::InvalidRequest(_))));
+        }
+    }
+}
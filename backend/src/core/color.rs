@@ -0,0 +1,171 @@
+use std::sync::OnceLock;
+
+use image::Rgba32FImage;
+use rayon::prelude::*;
+
+use crate::models::BlendColorSpace;
+
+/// Converts an sRGB encoded value in `[0,1]` to linear light.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear light value to sRGB encoding. Values above 1 are extrapolated.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Number of equally sized buckets of linear values, each knows the encoded value it starts with.
+const SRGB8_BUCKETS: usize = 4096;
+
+/// Linear values at which the 8-bit sRGB encoding changes, and a coarse index into them.
+struct Srgb8Table {
+    thresholds: Vec<f32>,
+    bucket_starts: Vec<u8>,
+}
+
+/// Part of an 8-bit step that a truncated value may lie below the next code and still be encoded
+/// as it. It absorbs the float error of converting an sRGB blend to linear light and back.
+pub const TRUNCATION_TOLERANCE: f32 = 1.0 / 1024.0;
+
+impl Srgb8Table {
+    /// Finds the linear values at which `encode`, a monotonic function to `[0,255]`, reaches
+    /// each code.
+    fn new(encode: impl Fn(f32) -> f32) -> Self {
+        let thresholds: Vec<f32> = (1..=255u32)
+            .map(|code| {
+                // Bisection over the bit patterns of positive floats, which sort like the floats
+                let (mut low, mut high) = (0u32, 1.0f32.to_bits());
+                while low < high {
+                    let middle = low + (high - low) / 2;
+                    if encode(f32::from_bits(middle)) >= code as f32 {
+                        high = middle;
+                    } else {
+                        low = middle + 1;
+                    }
+                }
+                f32::from_bits(low)
+            })
+            .collect();
+        let bucket_starts = (0..=SRGB8_BUCKETS)
+            .map(|bucket| {
+                let start = bucket as f32 / SRGB8_BUCKETS as f32;
+                thresholds.partition_point(|threshold| *threshold <= start) as u8
+            })
+            .collect();
+        Srgb8Table { thresholds, bucket_starts }
+    }
+
+    fn encode(&self, value: f32) -> u8 {
+        // NaN ends up at zero like with `max(0.0)`
+        if value.is_nan() || value <= 0.0 {
+            return 0;
+        }
+        if value >= 1.0 {
+            return 255;
+        }
+        // Scaling by a power of two is exact, so the bucket never starts above the value
+        let mut code = self.bucket_starts[(value * SRGB8_BUCKETS as f32) as usize];
+        while code < 255 && value >= self.thresholds[code as usize] {
+            code += 1;
+        }
+        code
+    }
+}
+
+/**
+
+Encodes a linear light value to an 8-bit sRGB channel value, clipping values outside `[0,1]`.
+Gives the same result as rounding `linear_to_srgb`, but looks the value up in a table of the
+linear values at which the encoded value changes instead of calling `powf`.
+
+ */
+pub fn linear_to_srgb8(value: f32) -> u8 {
+    static TABLE: OnceLock<Srgb8Table> = OnceLock::new();
+    TABLE
+        .get_or_init(|| Srgb8Table::new(|value| (linear_to_srgb(value) * 255.0).round()))
+        .encode(value)
+}
+
+/// Like `linear_to_srgb8`, but truncates the encoded value instead of rounding it, as the
+/// weighted mean did before frames were blended in linear light.
+pub fn linear_to_srgb8_truncated(value: f32) -> u8 {
+    static TABLE: OnceLock<Srgb8Table> = OnceLock::new();
+    TABLE
+        .get_or_init(|| {
+            Srgb8Table::new(|value| (linear_to_srgb(value) * 255.0 + TRUNCATION_TOLERANCE).floor())
+        })
+        .encode(value)
+}
+
+impl BlendColorSpace {
+    /// Lookup table from an 8-bit sRGB channel value to the value frames are blended with.
+    pub fn decoding_table(&self) -> [f32; 256] {
+        let mut table = [0.0; 256];
+        for (value, entry) in table.iter_mut().enumerate() {
+            let normalized = value as f32 / 255.0;
+            *entry = match self {
+                BlendColorSpace::Linear => srgb_to_linear(normalized),
+                BlendColorSpace::Srgb => normalized,
+            };
+        }
+        table
+    }
+
+    /// Converts the color channels of an image blended in this color space to linear light,
+    /// alpha stays untouched.
+    pub fn convert_to_linear(&self, img: &mut Rgba32FImage) {
+        if *self == BlendColorSpace::Linear {
+            return;
+        }
+        map_color_channels(img, |value| srgb_to_linear(value.max(0.0)));
+    }
+}
+
+/// Encodes the color channels of a linear light image to sRGB, alpha stays untouched.
+pub fn encode_srgb(img: &mut Rgba32FImage) {
+    map_color_channels(img, |value| linear_to_srgb(value.max(0.0)));
+}
+
+fn map_color_channels(img: &mut Rgba32FImage, map: impl Fn(f32) -> f32 + Sync) {
+    img.par_chunks_mut(4).for_each(|pixel| {
+        for channel in &mut pixel[..3] {
+            *channel = map(*channel);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_encoding_matches_powf() {
+        let exact = |value: f32| (linear_to_srgb(value.max(0.0)) * 255.0).round().clamp(0.0, 255.0) as u8;
+        for step in -10..=110_000 {
+            let value = step as f32 / 100_000.0;
+            assert_eq!(linear_to_srgb8(value), exact(value), "{}", value);
+        }
+        assert_eq!(linear_to_srgb8(7.5), 255);
+        assert_eq!(linear_to_srgb8(f32::NAN), 0);
+    }
+
+    #[test]
+    fn truncated_encoding_survives_the_linear_round_trip() {
+        for code in 0..=255u8 {
+            let encoded = code as f32 / 255.0;
+            assert_eq!(linear_to_srgb8_truncated(srgb_to_linear(encoded)), code);
+            // Half a step above the code still truncates to it
+            let above = (code as f32 + 0.5).min(255.0) / 255.0;
+            assert_eq!(linear_to_srgb8_truncated(srgb_to_linear(above)), code);
+        }
+    }
+}
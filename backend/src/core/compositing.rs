@@ -0,0 +1,175 @@
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma, Rgba32FImage, RgbaImage};
+use imageproc::filter::gaussian_blur_f32;
+use rayon::prelude::*;
+
+use crate::core::color::linear_to_srgb;
+use crate::models::{BlendColorSpace, MaskSettings};
+
+/// Per-pixel share of the long exposure blend in `[0,1]`, the rest is taken from the key frame.
+pub type BlendMask = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Longest name a mask can be uploaded with.
+pub const MAX_MASK_NAME_LENGTH: usize = 64;
+
+/// Name of the file a project mask is stored in, inside the project directory.
+pub fn mask_file_name(mask_name: &str) -> String {
+    format!("mask_{}.png", mask_name)
+}
+
+/// Mask names may only contain letters, digits, `-` and `_`, as they end up in a file name.
+pub fn is_valid_mask_name(mask_name: &str) -> bool {
+    !mask_name.is_empty()
+        && mask_name.len() <= MAX_MASK_NAME_LENGTH
+        && mask_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/**
+
+Loads a grayscale mask and turns it into a `BlendMask` for frames of the given dimensions.
+White areas show the long exposure blend, black areas the key frame.
+# Arguments
+- `mask_path`: The mask image, it is stretched to the frame dimensions if it differs in size.
+- `dimensions`: The dimensions of the frames.
+- `settings`: Whether to invert the mask and how far to feather its edges.
+# Returns
+- The prepared mask or an error message if it could not be read.
+
+ */
+pub fn load_blend_mask(
+    mask_path: &Path,
+    (width, height): (u32, u32),
+    settings: &MaskSettings,
+) -> Result<BlendMask, String> {
+    let mut mask = image::open(mask_path)
+        .map_err(|e| format!("Failed to open mask '{}': {}", settings.name, e))?
+        .to_luma32f();
+    if mask.dimensions() != (width, height) {
+        mask = imageops::resize(&mask, width, height, FilterType::Triangle);
+    }
+    if settings.invert {
+        mask.iter_mut().for_each(|value| *value = 1.0 - *value);
+    }
+    Ok(feather_mask(mask, settings.feather))
+}
+
+/// Softens the edges of a mask with a gaussian blur of `radius` pixels.
+pub fn feather_mask(mask: BlendMask, radius: f32) -> BlendMask {
+    if radius > 0.0 {
+        gaussian_blur_f32(&mask, radius)
+    } else {
+        mask
+    }
+}
+
+/**
+
+Separates the subject of a frame from the long exposure background by comparing them.
+Static parts of the scene look the same in both, so only pixels that differ clearly belong
+to the subject. The comparison uses the largest sRGB encoded channel difference, which follows
+the perceived difference more closely than linear light.
+# Arguments
+- `background`: The linear light long exposure render.
+- `frame`: The frame whose subject should be kept.
+- `threshold`: Difference from which on a pixel fully belongs to the subject, differences up
+  to half of it are treated as noise.
+# Returns
+- A `BlendMask` that keeps the background and is zero on the subject.
+
+ */
+pub fn difference_mask(background: &Rgba32FImage, frame: &RgbaImage, threshold: f32) -> BlendMask {
+    debug_assert_eq!(background.dimensions(), frame.dimensions());
+    let (width, height) = frame.dimensions();
+    let low = threshold * 0.5;
+    let mut mask: BlendMask = ImageBuffer::new(width, height);
+
+    mask.par_iter_mut()
+        .zip(background.as_raw().par_chunks(4))
+        .zip(frame.as_raw().par_chunks(4))
+        .for_each(|((share, background_pixel), frame_pixel)| {
+            let difference = background_pixel[..3]
+                .iter()
+                .zip(frame_pixel)
+                .map(|(background_value, value)| {
+                    (linear_to_srgb(background_value.clamp(0.0, 1.0)) - *value as f32 / 255.0).abs()
+                })
+                .fold(0.0, f32::max);
+            let subject = ((difference - low) / (threshold - low).max(f32::EPSILON)).clamp(0.0, 1.0);
+            *share = 1.0 - subject;
+        });
+
+    mask
+}
+
+/**
+
+Mixes a rendered long exposure image with a single frame, e.g. to keep a bridge sharp while
+the water below it is blurred. Mixing happens in linear light like the blending itself.
+# Arguments
+- `long_exposure_img`: The linear light render, it is modified in place.
+- `key_frame`: The frame shown where the mask is black.
+- `mask`: The share of the render per pixel.
+
+ */
+pub fn composite_with_key_frame(
+    long_exposure_img: &mut Rgba32FImage,
+    key_frame: &RgbaImage,
+    mask: &BlendMask,
+) {
+    debug_assert_eq!(long_exposure_img.dimensions(), key_frame.dimensions());
+    debug_assert_eq!(long_exposure_img.dimensions(), mask.dimensions());
+    let decoding_table = BlendColorSpace::Linear.decoding_table();
+
+    long_exposure_img
+        .par_chunks_mut(4)
+        .zip(key_frame.as_raw().par_chunks(4))
+        .zip(mask.as_raw().par_iter())
+        .for_each(|((out, key_pixel), share)| {
+            let share = share.clamp(0.0, 1.0);
+            for (channel, value) in out[..3].iter_mut().zip(key_pixel) {
+                *channel = *channel * share + decoding_table[*value as usize] * (1.0 - share);
+            }
+            out[3] = out[3] * share + key_pixel[3] as f32 / 255.0 * (1.0 - share);
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn mask_selects_between_blend_and_key_frame() {
+        let mut long_exposure_img: Rgba32FImage = ImageBuffer::from_pixel(3, 1, Rgba([1.0; 4]));
+        let key_frame: RgbaImage = ImageBuffer::from_pixel(3, 1, Rgba([0, 0, 0, 255]));
+        let mask: BlendMask = ImageBuffer::from_fn(3, 1, |x, _| Luma([x as f32 / 2.0]));
+
+        composite_with_key_frame(&mut long_exposure_img, &key_frame, &mask);
+
+        assert_eq!(long_exposure_img.get_pixel(0, 0).0, [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(long_exposure_img.get_pixel(1, 0).0, [0.5, 0.5, 0.5, 1.0]);
+        assert_eq!(long_exposure_img.get_pixel(2, 0).0, [1.0; 4]);
+    }
+
+    #[test]
+    fn difference_mask_keeps_only_the_subject() {
+        let background: Rgba32FImage = ImageBuffer::from_pixel(3, 1, Rgba([0.2, 0.2, 0.2, 1.0]));
+        let encoded = (linear_to_srgb(0.2) * 255.0).round() as u8;
+        let frame: RgbaImage = ImageBuffer::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([encoded, encoded, encoded, 255]),
+            1 => Rgba([encoded + 4, encoded, encoded, 255]),
+            _ => Rgba([255, encoded, encoded, 255]),
+        });
+
+        let mask = difference_mask(&background, &frame, 0.1);
+
+        assert_eq!(mask.get_pixel(0, 0).0, [1.0]);
+        assert_eq!(mask.get_pixel(1, 0).0, [1.0]);
+        assert_eq!(mask.get_pixel(2, 0).0, [0.0]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::path::{Path, PathBuf};
+
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+
+use crate::core::output::to_rgba8;
+use crate::models::OutputSettings;
+use crate::utils;
+
+/// Edge length of the square every thumbnail is fitted into.
+const THUMBNAIL_SIZE: u32 = 320;
+/// Space around the thumbnails and between a thumbnail and its label.
+const SPACING: u32 = 16;
+/// Every pixel of the 5x7 glyphs is drawn as a square of this size.
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const TEXT_COLOR: Rgba<u8> = Rgba([235, 235, 235, 255]);
+
+/// Scales a finished render down to a contact sheet thumbnail, tone mapped like its output.
+pub fn contact_sheet_thumbnail(long_exposure_img: &Rgba32FImage, output_settings: &OutputSettings) -> RgbaImage {
+    let (width, height) = long_exposure_img.dimensions();
+    let scale = (THUMBNAIL_SIZE as f32 / width.max(height).max(1) as f32).min(1.0);
+    let mut thumbnail = imageops::resize(
+        long_exposure_img,
+        ((width as f32 * scale).round() as u32).max(1),
+        ((height as f32 * scale).round() as u32).max(1),
+        FilterType::Triangle,
+    );
+    output_settings.tone_mapping.apply(&mut thumbnail);
+    to_rgba8(thumbnail)
+}
+
+/**
+
+Arranges labelled thumbnails in a grid that is about as wide as it is high.
+Each thumbnail is centered in its cell and its label is written below it, cut to the cell width.
+# Arguments
+- `thumbnails`: The labels and thumbnails, row by row.
+# Returns
+- The contact sheet.
+
+ */
+pub fn render_contact_sheet(thumbnails: &[(String, RgbaImage)]) -> RgbaImage {
+    let columns = (thumbnails.len() as f32).sqrt().ceil().max(1.0) as u32;
+    let rows = (thumbnails.len() as u32).div_ceil(columns);
+    let cell_width = THUMBNAIL_SIZE + SPACING;
+    let cell_height = THUMBNAIL_SIZE + SPACING + GLYPH_HEIGHT * GLYPH_SCALE + SPACING;
+
+    let mut sheet = ImageBuffer::from_pixel(
+        columns * cell_width + SPACING,
+        rows * cell_height + SPACING,
+        BACKGROUND,
+    );
+    for (index, (label, thumbnail)) in thumbnails.iter().enumerate() {
+        let cell_x = SPACING + (index as u32 % columns) * cell_width;
+        let cell_y = SPACING + (index as u32 / columns) * cell_height;
+        let (width, height) = thumbnail.dimensions();
+        imageops::overlay(
+            &mut sheet,
+            thumbnail,
+            (cell_x + THUMBNAIL_SIZE.saturating_sub(width) / 2) as i64,
+            (cell_y + THUMBNAIL_SIZE.saturating_sub(height) / 2) as i64,
+        );
+        draw_label(&mut sheet, label, cell_x, cell_y + THUMBNAIL_SIZE + SPACING, THUMBNAIL_SIZE);
+    }
+    sheet
+}
+
+/// Renders the contact sheet and saves it as PNG in the given directory.
+pub fn save_contact_sheet(thumbnails: &[(String, RgbaImage)], output_dir: &Path) -> Result<PathBuf, String> {
+    let contact_sheet_path = utils::generate_timestamped_path(output_dir, "contact_sheet", "png");
+    render_contact_sheet(thumbnails)
+        .save(&contact_sheet_path)
+        .map_err(|e| format!("Failed to save contact sheet: {}", e))?;
+    Ok(contact_sheet_path)
+}
+
+/// Writes a single line of text, characters that do not fit into `max_width` are left out.
+fn draw_label(sheet: &mut RgbaImage, label: &str, x: u32, y: u32, max_width: u32) {
+    let advance = (GLYPH_WIDTH + 1) * GLYPH_SCALE;
+    for (index, character) in label.chars().take((max_width / advance) as usize).enumerate() {
+        let glyph_x = x + index as u32 * advance;
+        for (row, bits) in glyph(character).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for dy in 0..GLYPH_SCALE {
+                    for dx in 0..GLYPH_SCALE {
+                        sheet.put_pixel(
+                            glyph_x + column * GLYPH_SCALE + dx,
+                            y + row as u32 * GLYPH_SCALE + dy,
+                            TEXT_COLOR,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a 5x7 glyph from top to bottom, the highest of the five bits is the leftmost column.
+/// Lowercase letters are drawn as uppercase, unknown characters as `?`. German umlauts and `ß` are
+/// included, as labels are often written in German.
+fn glyph(character: char) -> [u8; 7] {
+    // `ß` has no single uppercase character
+    let character = match character {
+        'ß' => 'ß',
+        _ => character.to_uppercase().next().unwrap_or(character),
+    };
+    match character {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        'Ä' => [0x0A, 0x00, 0x0E, 0x11, 0x1F, 0x11, 0x11],
+        'Ö' => [0x0A, 0x00, 0x0E, 0x11, 0x11, 0x11, 0x0E],
+        'Ü' => [0x0A, 0x00, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'ß' => [0x0C, 0x12, 0x12, 0x14, 0x12, 0x11, 0x16],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '\'' => [0x0C, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contact_sheet_places_thumbnails_and_labels() {
+        let thumbnail: RgbaImage = ImageBuffer::from_pixel(THUMBNAIL_SIZE, 10, Rgba([255, 0, 0, 255]));
+        let thumbnails: Vec<(String, RgbaImage)> =
+            (1..=3).map(|index| (format!("Variant {}", index), thumbnail.clone())).collect();
+
+        let sheet = render_contact_sheet(&thumbnails);
+        // Three thumbnails fit into a grid of two columns and two rows
+        assert_eq!(sheet.width(), 2 * (THUMBNAIL_SIZE + SPACING) + SPACING);
+        let cell_height = THUMBNAIL_SIZE + 2 * SPACING + GLYPH_HEIGHT * GLYPH_SCALE;
+        assert_eq!(sheet.height(), 2 * cell_height + SPACING);
+
+        let thumbnail_y = SPACING + (THUMBNAIL_SIZE - 10) / 2;
+        assert_eq!(*sheet.get_pixel(SPACING, thumbnail_y), Rgba([255, 0, 0, 255]));
+        // Top left pixel of the `V` of the first label
+        assert_eq!(*sheet.get_pixel(SPACING, SPACING + THUMBNAIL_SIZE + SPACING), TEXT_COLOR);
+        // The fourth cell stays empty
+        let empty = sheet.get_pixel(SPACING + THUMBNAIL_SIZE + SPACING, SPACING + cell_height + thumbnail_y);
+        assert_eq!(*empty, BACKGROUND);
+    }
+
+    #[test]
+    fn umlauts_have_their_own_glyphs() {
+        let unknown = glyph('?');
+        for (lowercase, uppercase) in [('ä', 'Ä'), ('ö', 'Ö'), ('ü', 'Ü')] {
+            assert_eq!(glyph(lowercase), glyph(uppercase));
+            assert_ne!(glyph(uppercase), unknown);
+        }
+        assert_ne!(glyph('ß'), unknown);
+    }
+}
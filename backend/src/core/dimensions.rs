@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::error::{MismatchedFrame, RenderError};
+use crate::models::{CropRectangle, DimensionPolicy, Rotation};
+
+/**
+
+Reads the dimensions of all frames of a render from their file headers, without decoding them,
+and checks them against the first frame. Frames of other dimensions are an error unless the
+policy fits them to the reference dimensions while loading.
+# Arguments
+- `frame_paths`: The frame numbers and files of all frames the render reads, the first one
+  is the reference.
+- `policy`: What to do with frames whose dimensions differ from the reference.
+# Returns
+- The reference dimensions, or an error naming every frame that does not match them.
+
+ */
+pub fn validate_frame_dimensions(
+    frame_paths: &[(usize, PathBuf)],
+    policy: DimensionPolicy,
+) -> Result<(u32, u32), RenderError> {
+    let frame_dimensions = frame_paths
+        .par_iter()
+        .map(|(frame_number, frame_path)| {
+            image::image_dimensions(frame_path)
+                .map(|dimensions| (*frame_number, dimensions))
+                .map_err(|e| RenderError::Failed(format!("Failed to read frame {}: {}", frame_number, e)))
+        })
+        .collect::<Result<Vec<_>, RenderError>>()?;
+
+    let Some((_, expected)) = frame_dimensions.first().copied() else {
+        return Err(RenderError::Failed("No images were chosen".to_string()));
+    };
+    if policy != DimensionPolicy::Reject {
+        return Ok(expected);
+    }
+
+    let mut mismatched: Vec<MismatchedFrame> = frame_dimensions
+        .into_iter()
+        .filter(|(_, dimensions)| *dimensions != expected)
+        .map(|(frame_number, dimensions)| MismatchedFrame {
+            frame_number,
+            dimensions,
+        })
+        .collect();
+    if mismatched.is_empty() {
+        return Ok(expected);
+    }
+    mismatched.sort_unstable_by_key(|frame| frame.frame_number);
+    mismatched.dedup();
+    Err(RenderError::DimensionMismatch {
+        expected,
+        frames: mismatched,
+    })
+}
+
+/// Fits a frame to the reference dimensions according to the policy.
+/// Frames that already match, and all frames under `DimensionPolicy::Reject`, are returned as they are.
+pub fn conform_frame(img: RgbaImage, (width, height): (u32, u32), policy: DimensionPolicy) -> RgbaImage {
+    if img.dimensions() == (width, height) {
+        return img;
+    }
+    match policy {
+        DimensionPolicy::Reject => img,
+        DimensionPolicy::Resample => imageops::resize(&img, width, height, FilterType::CatmullRom),
+        DimensionPolicy::Crop => {
+            // Centers the frame on the reference, parts outside are cut off and uncovered
+            // parts stay transparent so they do not contribute to the blend
+            let mut conformed: RgbaImage = ImageBuffer::from_pixel(width, height, Rgba([0, 0, 0, 0]));
+            let x = (width as i64 - img.width() as i64) / 2;
+            let y = (height as i64 - img.height() as i64) / 2;
+            imageops::replace(&mut conformed, &img, x, y);
+            conformed
+        }
+    }
+}
+
+/**
+
+Resolves the part of the frames a render reads. Crop rectangles are given in pixels of the full
+size frames, for previews they are scaled to the thumbnails and grown to whole pixels.
+# Arguments
+- `crop`: The crop rectangle of the request, the whole frame if not set.
+- `dimensions`: The dimensions of the frames that are rendered.
+- `full_dimensions`: The dimensions of the full size frames.
+# Returns
+- The region in pixels of the rendered frames, or an invalid request error if the crop rectangle
+  is empty or does not fit into the frames.
+
+ */
+pub fn crop_region(
+    crop: Option<CropRectangle>,
+    (width, height): (u32, u32),
+    (full_width, full_height): (u32, u32),
+) -> Result<CropRectangle, RenderError> {
+    let Some(crop) = crop else {
+        return Ok(CropRectangle { x: 0, y: 0, width, height });
+    };
+    if crop.width == 0
+        || crop.height == 0
+        || crop.x as u64 + crop.width as u64 > full_width as u64
+        || crop.y as u64 + crop.height as u64 > full_height as u64
+    {
+        return Err(RenderError::InvalidRequest(format!(
+            "Crop rectangle {}x{} at ({}, {}) does not fit into the {}x{} frames",
+            crop.width, crop.height, crop.x, crop.y, full_width, full_height
+        )));
+    }
+    if (width, height) == (full_width, full_height) {
+        return Ok(crop);
+    }
+
+    let scale_span = |start: u32, length: u32, size: u32, full_size: u32| {
+        let scale = size as f64 / full_size as f64;
+        let scaled_start = ((start as f64 * scale).floor() as u32).min(size - 1);
+        let scaled_end = (((start + length) as f64 * scale).ceil() as u32).clamp(scaled_start + 1, size);
+        (scaled_start, scaled_end - scaled_start)
+    };
+    let (x, scaled_width) = scale_span(crop.x, crop.width, width, full_width);
+    let (y, scaled_height) = scale_span(crop.y, crop.height, height, full_height);
+    Ok(CropRectangle {
+        x,
+        y,
+        width: scaled_width,
+        height: scaled_height,
+    })
+}
+
+impl Rotation {
+    pub fn apply(&self, img: Rgba32FImage) -> Rgba32FImage {
+        match self {
+            Rotation::None => img,
+            Rotation::Cw90 => imageops::rotate90(&img),
+            Rotation::Cw180 => imageops::rotate180(&img),
+            Rotation::Cw270 => imageops::rotate270(&img),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_turns_the_image_clockwise() {
+        let img: Rgba32FImage = ImageBuffer::from_fn(3, 2, |x, y| Rgba([x as f32, y as f32, 0.0, 1.0]));
+        let rotated = Rotation::Cw90.apply(img.clone());
+        assert_eq!(rotated.dimensions(), (2, 3));
+        // The bottom left corner becomes the top left one
+        assert_eq!(rotated.get_pixel(0, 0).0, [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(Rotation::Cw180.apply(img.clone()).get_pixel(0, 0).0, [2.0, 1.0, 0.0, 1.0]);
+        assert_eq!(Rotation::Cw270.apply(rotated), img);
+        assert_eq!(Rotation::None.apply(img.clone()), img);
+    }
+
+    #[test]
+    fn crop_centers_frame_on_reference() {
+        let larger: RgbaImage = ImageBuffer::from_fn(4, 4, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        let cropped = conform_frame(larger, (2, 2), DimensionPolicy::Crop);
+        assert_eq!(cropped.get_pixel(0, 0).0, [1, 1, 0, 255]);
+        assert_eq!(cropped.get_pixel(1, 1).0, [2, 2, 0, 255]);
+
+        let smaller: RgbaImage = ImageBuffer::from_pixel(2, 2, Rgba([9, 9, 9, 255]));
+        let padded = conform_frame(smaller, (4, 4), DimensionPolicy::Crop);
+        assert_eq!(padded.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(padded.get_pixel(1, 1).0, [9, 9, 9, 255]);
+        assert_eq!(padded.get_pixel(3, 3).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn crop_region_is_scaled_to_thumbnails() {
+        let crop = CropRectangle { x: 100, y: 50, width: 201, height: 100 };
+        assert_eq!(crop_region(Some(crop), (800, 600), (800, 600)).unwrap(), crop);
+        assert_eq!(
+            crop_region(Some(crop), (200, 150), (800, 600)).unwrap(),
+            CropRectangle { x: 25, y: 12, width: 51, height: 26 }
+        );
+        for invalid in [CropRectangle { x: 700, ..crop }, CropRectangle { width: 0, ..crop }] {
+            assert!(matches!(
+                crop_region(Some(invalid), (800, 600), (800, 600)),
+                Err(RenderError::InvalidRequest(_))
+            ));
+        }
+        assert_eq!(
+            crop_region(None, (200, 150), (800, 600)).unwrap(),
+            CropRectangle { x: 0, y: 0, width: 200, height: 150 }
+        );
+    }
+}
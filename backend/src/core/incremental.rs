@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use image::{Rgba32FImage, RgbaImage};
+use tracing::debug;
+
+use crate::core::accumulator::{Accumulator, BlendSettings};
+use crate::core::long_exposure_image_logic::{
+    accumulate_frames, contributing_frames, stream_frames, FramePreparation,
+};
+use crate::models::FrameData;
+
+/// After this many incremental updates the state is rebuilt from scratch, so rounding
+/// errors of repeated additions and removals cannot pile up.
+const MAX_INCREMENTAL_UPDATES: usize = 32;
+
+/**
+
+Accumulation buffers of a previous render together with the settings and frames they were
+built from, including how the frames were prepared (e.g. aligned). For blend modes whose result
+is a plain sum (see `BlendMode::is_reversible`) a following render only needs to add, remove or
+reweight the frames that changed.
+
+ */
+pub struct AccumulationState {
+    frame_weights: HashMap<usize, f32>,
+    preparation: FramePreparation,
+    accumulator: Accumulator<f64>,
+    incremental_updates: usize,
+}
+
+impl AccumulationState {
+    fn is_compatible(&self, settings: &BlendSettings, preparation: &FramePreparation) -> bool {
+        self.accumulator.settings() == settings && self.preparation == *preparation
+    }
+
+    /// Frames whose weight differs from the requested one, as `(frame_number, old, new)`.
+    /// A weight of zero means the frame is not part of the selection.
+    fn changed_frames(&self, frame_weights: &HashMap<usize, f32>) -> Vec<(usize, f32, f32)> {
+        let mut changes: Vec<(usize, f32, f32)> = frame_weights
+            .iter()
+            .map(|(frame_number, weight)| {
+                let old_weight = self.frame_weights.get(frame_number).copied().unwrap_or(0.0);
+                (*frame_number, old_weight, *weight)
+            })
+            .chain(
+                self.frame_weights
+                    .iter()
+                    .filter(|(frame_number, _)| !frame_weights.contains_key(frame_number))
+                    .map(|(frame_number, old_weight)| (*frame_number, *old_weight, 0.0)),
+            )
+            .filter(|(_, old_weight, weight)| old_weight != weight)
+            .collect();
+        changes.sort_unstable_by_key(|(frame_number, _, _)| *frame_number);
+        changes
+    }
+
+    fn apply_changes<L>(&mut self, changes: &[(usize, f32, f32)], load_frame: L) -> Result<(), String>
+    where
+        L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+    {
+        let frame_numbers: Vec<usize> = changes.iter().map(|(frame_number, _, _)| *frame_number).collect();
+        let accumulator = &mut self.accumulator;
+        // Reversible modes accumulate frames with their raw weight as factor
+        stream_frames(&frame_numbers, accumulator.dimensions(), load_frame, |index, img| {
+            let (_, old_weight, weight) = changes[index];
+            if old_weight == 0.0 {
+                accumulator.add_frame(img, weight);
+            } else if weight == 0.0 {
+                accumulator.remove_frame(img, old_weight);
+            } else {
+                accumulator.reweight_frame(img, old_weight, weight);
+            }
+        })
+    }
+}
+
+/**
+
+Renders a reversible blend mode, reusing the accumulation state of a previous render when
+its settings match and only a part of the frame selection changed.
+# Arguments
+- `previous_state`: The state returned by the previous render of the same project, if any.
+- `frames_data`: The frames to include and their weights.
+- `settings`: The blend settings of the render, the blend mode has to be reversible.
+- `preparation`: How `load_frame` prepares the frames, a state prepared differently is not reused.
+- `load_frame`: Decodes a frame by its frame number.
+# Returns
+- The blended image and the state to pass to the next render, or an error message.
+
+ */
+pub fn render_incrementally<L>(
+    previous_state: Option<AccumulationState>,
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    preparation: &FramePreparation,
+    load_frame: L,
+) -> Result<(Rgba32FImage, AccumulationState), String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+{
+    debug_assert!(settings.blend_mode.is_reversible());
+    let frames_data = contributing_frames(frames_data)?;
+
+    // A frame listed twice counts twice, which for a sum is the same as adding up its weights
+    let mut frame_weights: HashMap<usize, f32> = HashMap::new();
+    for frame in &frames_data {
+        *frame_weights.entry(frame.frame_number).or_insert(0.0) += frame.frame_weight;
+    }
+
+    if let Some(mut state) =
+        previous_state.filter(|state| state.is_compatible(settings, preparation))
+    {
+        let changes = state.changed_frames(&frame_weights);
+        // Applying a change costs about as much as decoding a frame for a full render
+        if changes.len() < frame_weights.len()
+            && state.incremental_updates < MAX_INCREMENTAL_UPDATES
+        {
+            debug!("Updating accumulation state with {} changed frames", changes.len());
+            state.apply_changes(&changes, load_frame)?;
+            state.frame_weights = frame_weights;
+            state.incremental_updates += 1;
+            return Ok((state.accumulator.finish(), state));
+        }
+    }
+
+    let accumulator: Accumulator<f64> =
+        accumulate_frames(&frames_data, settings, load_frame)?;
+    let state = AccumulationState {
+        frame_weights,
+        preparation: preparation.clone(),
+        accumulator,
+        incremental_updates: 0,
+    };
+    Ok((state.accumulator.finish(), state))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+    use crate::core::test_support::linear_settings;
+    use crate::models::BlendMode;
+
+    fn frames(weights: &[(usize, f32)]) -> Vec<FrameData> {
+        weights
+            .iter()
+            .map(|(frame_number, frame_weight)| FrameData {
+                frame_number: *frame_number,
+                frame_weight: *frame_weight,
+                freeze: false,
+            })
+            .collect()
+    }
+
+    fn load_synthetic_frame(frame_number: usize) -> Result<RgbaImage, String> {
+        Ok(ImageBuffer::from_fn(3, 2, |x, y| {
+            let value = ((frame_number * 37 + x as usize * 11 + y as usize * 53) % 256) as u8;
+            // The last column is black in all but one frame
+            if x == 2 && frame_number != 4 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([value, 255 - value, value / 2, 255])
+            }
+        }))
+    }
+
+    #[test]
+    fn incremental_update_matches_full_render() {
+        let settings = linear_settings(BlendMode::WeightedMean);
+        let preparation = FramePreparation::default();
+        let first = frames(&[(1, 1.0), (2, 1.0), (3, 2.0), (4, 1.0), (5, 0.5)]);
+        let second = frames(&[(1, 1.0), (2, 1.0), (3, 0.5), (5, 0.5), (6, 1.0)]);
+
+        let (_, state) =
+            render_incrementally(None, &first, &settings, &preparation, load_synthetic_frame)
+                .unwrap();
+        let (updated, state) = render_incrementally(
+            Some(state),
+            &second,
+            &settings,
+            &preparation,
+            load_synthetic_frame,
+        )
+        .unwrap();
+        assert_eq!(state.incremental_updates, 1);
+
+        let (full, _) =
+            render_incrementally(None, &second, &settings, &preparation, load_synthetic_frame)
+                .unwrap();
+        for (updated_value, full_value) in updated.as_raw().iter().zip(full.as_raw()) {
+            assert!((updated_value - full_value).abs() < 1e-5);
+        }
+        // Removing the only frame that lit the last column leaves it empty again
+        assert_eq!(updated.get_pixel(2, 0).0, [0.0; 4]);
+    }
+}
@@ -1,155 +1,1228 @@
-use std::path::PathBuf;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use image::{ImageBuffer, Rgba, RgbaImage};
+#[cfg(debug_assertions)]
+use chrono::Utc;
+use image::{imageops, Rgba32FImage, RgbaImage};
 use rayon::prelude::*;
+#[cfg(debug_assertions)]
+use tracing::debug;
+use uuid::Uuid;
 
-use crate::models::FrameData;
-use crate::utils;
+use crate::core::accumulator::{frame_factors, Accumulator, AccumulatorValue, BlendSettings};
+use crate::core::adjustments::apply_adjustments;
+use crate::core::alignment::{read_cached_transforms, write_cached_transforms, FrameAligner};
+use crate::core::animation::render_animation;
+use crate::core::compositing::{
+    composite_with_key_frame, difference_mask, feather_mask, is_valid_mask_name, load_blend_mask, mask_file_name,
+    BlendMask,
+};
+use crate::core::dimensions::{conform_frame, crop_region, validate_frame_dimensions};
+use crate::core::incremental::{render_incrementally, AccumulationState};
+use crate::core::output::{save_long_exposure_image, write_render_record, MAX_OUTPUT_DIMENSION};
+use crate::core::sample_stack::{render_in_bands, stack_pixel_fn};
+use crate::core::selection::expand_frame_selection;
+use crate::core::stroboscopic::composite_stroboscopic;
+use crate::core::tiling::{crop_region_of, png_readable_by_rows, read_png_region, render_tiled};
+use crate::core::time_slice::render_time_slice;
+use crate::error::RenderError;
+use crate::models::{
+    AlignmentSettings, Animation, BlendColorSpace, BlendMode, CreateLongExposureImageRequest,
+    CreateLongExposureImageResponse, CropRectangle, DimensionPolicy, FrameData, MaskSettings,
+    RejectionStatistics,
+};
 use crate::utils::convert_image_path_to_serving_url;
 
+/// How long previews stay in the `previews` directory of a project, long enough for the client to
+/// fetch them.
+pub const PREVIEW_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// Everything that changes the frames before they are blended. Accumulation states are only
+/// reused by renders that prepare their frames the same way.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FramePreparation {
+    /// Whether the low resolution thumbnails are blended instead of the frames.
+    pub preview: bool,
+    /// How frames of other dimensions are fitted to the first selected frame.
+    pub dimension_policy: DimensionPolicy,
+    /// Alignment of the frames, with the reference frame resolved.
+    pub alignment: Option<AlignmentSettings>,
+    /// Part of the frames that is rendered.
+    pub crop: Option<CropRectangle>,
+}
+
 /**
 
 Creates a long-exposure image by blending multiple frames with their associated weights.
+Frame ranges of the request are expanded into single frames first, with weights interpolated from keyframes.
+The dimensions of all frames are checked before anything is decoded. Frames that differ from the first
+selected frame are an error unless the request asks to resample or crop them.
 The blending takes into account pixel brightness and alpha values to adjust each frame's contribution.
+If requested, frames are aligned to a reference frame first. The estimated transforms are cached in the
+frames directory, so later renders of the project only estimate the frames they have not seen yet.
+With a mask, the blend is only used inside the mask and a key frame is shown outside of it.
+The subject of a frame marked with `freeze` is composited sharp on top of the result.
+The post-processing adjustments of the request run last, before the image is encoded. The request is
+recorded in a JSON file next to the image, so the render can be repeated with the same settings.
+Previews blend the frame thumbnails instead and are written to the `previews` directory of the project
+under a unique name, previews older than `PREVIEW_RETENTION` are removed from it.
+Frames are streamed through the renderer, so the memory usage does not grow with the number of frames.
+With a crop rectangle only that part of the frames is read and blended, and the result can be rotated.
+Very large frames can be rendered in tiles, which only read the rows of each frame they need.
+Tiled renders do not keep an accumulation state.
+If requested, an animation of the frames is rendered with the same settings and written next to the image.
 # Arguments
 - `frames_dir_path`: The directory where the frames are located.
-- `frames_data`: A vector of `FrameData` which contains information such as frame number and weight.
+- `request`: The render request with the frames to include, their weights and the blend settings.
+- `project_color_space`: The blend color space of the project, used if the request does not set one.
+- `previous_state`: The accumulation state of the previous render of this project, if any.
+  Reversible blend modes only apply the frames that changed since then.
 # Returns
-- A `Result` containing the image source as usable url of the generated image on success, or an error message on failure.
+- A `Result` containing the response with the image source as usable url of the generated image
+  and the accumulation state to pass to the next render on success, or the error on failure.
 
  */
 pub async fn create_long_exposure_image(
     frames_dir_path: PathBuf,
-    frames_data: Vec<FrameData>,
-) -> Result<String, String> {
-    #[cfg(debug)]
+    mut request: CreateLongExposureImageRequest,
+    project_color_space: BlendColorSpace,
+    previous_state: Option<AccumulationState>,
+) -> Result<(CreateLongExposureImageResponse, Option<AccumulationState>), RenderError> {
+    #[cfg(debug_assertions)]
     let start_time = Utc::now();
 
-    // Collect images and their user-specified weights
-    let image_buffers: Vec<(RgbaImage, f32)> = frames_data
-        .par_iter()
-        .map(|frame| {
-            let frame_file_name = format!("ffout_{:04}.png", frame.frame_number);
-            let frame_path = frames_dir_path.join(frame_file_name);
-            let img = image::open(&frame_path)
-                .map_err(|e| format!("Failed to open image: {}", e))?
-                .to_rgba8();
-            Ok::<(RgbaImage, f32), String>((img, frame.frame_weight))
-        })
-        .collect::<Result<Vec<_>, String>>()?;
+    let (render_record, settings, preparation) =
+        prepare_request(&frames_dir_path, &mut request, project_color_space)?;
 
-    #[cfg(debug)]
-    let file_processing_end_time = Utc::now();
+    let frame_numbers = frames_read_by(&request, &preparation);
+    let source = FrameSource::new(&frames_dir_path, frame_numbers.clone(), &preparation)?;
+    let load_rendered = |frame_number| source.load_rendered(frame_number);
 
-    if image_buffers.is_empty() {
-        return Err("No images were chosen".to_string());
+    let tiled_region = request.tile_rows.zip(source.region_dimensions());
+    let (long_exposure_img, accumulation_state, rejection) = if let Some((tile_rows, dimensions)) =
+        tiled_region
+    {
+        source.check_partial_reads(&frame_numbers)?;
+        let (img, rejection) = render_tiled(
+            &request.frames_to_include,
+            &settings,
+            dimensions,
+            tile_rows,
+            |frame_number, rows| source.load_region(frame_number, rows),
+        )?;
+        (img, None, rejection)
+    } else if settings.blend_mode.is_reversible() {
+        let (img, state) = render_incrementally(
+            previous_state,
+            &request.frames_to_include,
+            &settings,
+            &preparation,
+            load_rendered,
+        )?;
+        (img, Some(state), None)
+    } else {
+        let dimensions = source
+            .region_dimensions()
+            .ok_or_else(|| "No images were chosen".to_string())?;
+        let (img, rejection) = render_frames(&request.frames_to_include, &settings, dimensions, load_rendered)?;
+        (img, None, rejection)
+    };
+
+    let plates = FinishingPlates::load(&request, &source, long_exposure_img.dimensions())?;
+    let long_exposure_img = finish_render(long_exposure_img, &request, &plates);
+
+    #[cfg(debug_assertions)]
+    debug!(
+        "Long Exposure Image Calculations = {}",
+        (Utc::now() - start_time).num_milliseconds()
+    );
+
+    let project_dir_path = frames_dir_path.join("..");
+    let (output_dir_path, base_name) = if request.preview {
+        let previews_dir_path = project_dir_path.join("previews");
+        prune_output_dir(&previews_dir_path, PREVIEW_RETENTION)?;
+        // Concurrent previews of a project must not overwrite each other
+        (previews_dir_path, format!("preview_{}", Uuid::new_v4().simple()))
+    } else {
+        (project_dir_path, "long_exposure_image".to_string())
+    };
+    let long_exposure_image_file_path =
+        save_long_exposure_image(long_exposure_img, &request.output, &settings, &output_dir_path, &base_name)?;
+    write_render_record(&long_exposure_image_file_path, &render_record)?;
+
+    let animation_url = match &request.animation {
+        Some(animation) => {
+            let animation_path =
+                render_animation(animation, &request, &settings, &source, &plates, &output_dir_path).await?;
+            Some(convert_image_path_to_serving_url(&animation_path).await)
+        }
+        None => None,
+    };
+    source.store_alignment();
+
+    let response = CreateLongExposureImageResponse {
+        image_url: convert_image_path_to_serving_url(&long_exposure_image_file_path).await,
+        rejection,
+        animation_url,
+    };
+    Ok((response, accumulation_state))
+}
+
+/**
+
+Expands the frame selection of a request, checks it and resolves the settings it is rendered with.
+Everything that makes a request invalid is found here, before any frame is decoded.
+# Arguments
+- `frames_dir_path`: The directory where the frames are located, masks are stored next to it.
+- `request`: The render request, its frame ranges and weight keyframes are expanded into
+  `frames_to_include`.
+- `project_color_space`: The blend color space of the project, used if the request does not set one.
+# Returns
+- The request as it is recorded next to the image, with the frame ranges as sent and the color
+  space resolved, the blend settings and how the frames are prepared, or an error.
+
+ */
+pub fn prepare_request(
+    frames_dir_path: &Path,
+    request: &mut CreateLongExposureImageRequest,
+    project_color_space: BlendColorSpace,
+) -> Result<(CreateLongExposureImageRequest, BlendSettings, FramePreparation), RenderError> {
+    // Recorded as sent, so the frame ranges stay readable
+    let mut render_record = request.clone();
+
+    let last_frame = if request.frame_ranges.is_empty() {
+        0
+    } else {
+        last_frame_number(frames_dir_path)
+    };
+    request.frames_to_include = expand_frame_selection(
+        &request.frame_ranges,
+        &request.weight_keyframes,
+        &request.frames_to_include,
+        last_frame,
+    )?;
+
+    let settings = BlendSettings {
+        blend_mode: request.blend_mode,
+        weighting: request.weighting.clone(),
+        color_space: request.color_space.unwrap_or(project_color_space),
+    };
+    render_record.color_space = Some(settings.color_space);
+    let preparation = FramePreparation {
+        preview: request.preview,
+        dimension_policy: request.dimension_policy,
+        alignment: resolve_alignment(request.alignment, &request.frames_to_include),
+        crop: request.crop,
+    };
+    if let Some(mask) = &request.mask {
+        mask_path(frames_dir_path, &mask.name)?;
+    }
+    frozen_frame(&request.frames_to_include)?;
+    // Tiles would decode every frame completely, only unmodified frames are read partially
+    let reads_unmodified_frames = preparation.alignment.is_none()
+        && !preparation.preview
+        && preparation.dimension_policy == DimensionPolicy::Reject;
+    if let Some(tile_rows) = request.tile_rows {
+        if tile_rows == 0 {
+            return Err(RenderError::InvalidRequest("tile_rows has to be at least 1".to_string()));
+        }
+        if !reads_unmodified_frames {
+            return Err(RenderError::InvalidRequest(
+                "tile_rows cannot be combined with alignment, previews or resampled frames".to_string(),
+            ));
+        }
+        // The subjects are feathered across tile borders and would need whole frames
+        if matches!(settings.blend_mode, BlendMode::Stroboscopic { .. }) {
+            return Err(RenderError::InvalidRequest(
+                "tile_rows cannot be combined with the stroboscopic blend mode".to_string(),
+            ));
+        }
+    }
+    let animation_kind = request.animation.as_ref().map(|animation| animation.kind);
+    if matches!(animation_kind, Some(Animation::BuildUp { .. })) && !settings.blend_mode.is_streamable() {
+        return Err(RenderError::InvalidRequest(
+            "Build-up animations need a streamable blend mode, median, sigma_clipping, stroboscopic \
+             and time_slice are not supported"
+                .to_string(),
+        ));
+    }
+    let is_time_slice = matches!(settings.blend_mode, BlendMode::TimeSlice { .. });
+    if is_time_slice && !request.frames_to_include.iter().all(|frame| frame.frame_weight > 0.0) {
+        return Err(RenderError::InvalidRequest(
+            "The frame weights of a time slice are the slice widths and must be positive".to_string(),
+        ));
     }
+    let output_size = [request.output.width, request.output.height];
+    if output_size.into_iter().flatten().any(|side| side > MAX_OUTPUT_DIMENSION) {
+        return Err(RenderError::InvalidRequest(format!(
+            "The output width and height can be at most {} pixels",
+            MAX_OUTPUT_DIMENSION
+        )));
+    }
+    Ok((render_record, settings, preparation))
+}
 
-    let (width, height) = image_buffers[0].0.dimensions();
-    let mut long_exposure_img: RgbaImage = ImageBuffer::new(width, height);
+/**
 
-    // Normalize frame weights
-    let total_frame_weight: f32 = image_buffers.par_iter().map(|(_, weight)| *weight).sum();
-    if total_frame_weight == 0.0 {
-        return Err("Total frame weight cannot be zero".to_string());
+Decodes the frames of a render and prepares them for blending: frames of other dimensions are
+fitted to the reference frame, frames are aligned and the rendered region is cut out of them.
+
+ */
+pub struct FrameSource {
+    frames_dir_path: PathBuf,
+    preview: bool,
+    dimension_policy: DimensionPolicy,
+    /// Dimensions of the frames, `None` if the render reads no frame at all.
+    dimensions: Option<(u32, u32)>,
+    /// Part of the frames that is rendered, the whole frame without a crop rectangle.
+    region: Option<CropRectangle>,
+    /// The aligner and the number of transforms it was created with from the cache.
+    aligner: Option<(usize, FrameAligner)>,
+}
+
+impl FrameSource {
+    /**
+
+    Checks the dimensions of all frames before anything is decoded, resolves the rendered region
+    and estimates the alignment reference.
+    # Arguments
+    - `frames_dir_path`: The directory where the frames are located.
+    - `frame_numbers`: Every frame the render reads, the first one is the reference for the dimensions.
+    - `preparation`: How the frames are prepared.
+    # Returns
+    - The frame source, or an error if frames do not match or the crop rectangle does not fit.
+
+     */
+    pub fn new(
+        frames_dir_path: &Path,
+        frame_numbers: Vec<usize>,
+        preparation: &FramePreparation,
+    ) -> Result<Self, RenderError> {
+        let frame_paths: Vec<(usize, PathBuf)> = frame_numbers
+            .into_iter()
+            .map(|frame_number| (frame_number, frame_path(frames_dir_path, frame_number, preparation.preview)))
+            .collect();
+        // Without a selected frame there is nothing to validate, rendering reports the empty selection
+        let dimensions = if frame_paths.is_empty() {
+            None
+        } else {
+            Some(validate_frame_dimensions(&frame_paths, preparation.dimension_policy)?)
+        };
+        let region = match dimensions {
+            Some(dimensions) => {
+                let full_dimensions = if preparation.preview {
+                    let reference_frame = frame_paths[0].0;
+                    image::image_dimensions(frame_path(frames_dir_path, reference_frame, false))
+                        .map_err(|e| format!("Failed to read frame {}: {}", reference_frame, e))?
+                } else {
+                    dimensions
+                };
+                Some(crop_region(preparation.crop, dimensions, full_dimensions)?)
+            }
+            None => None,
+        };
+
+        let mut source = FrameSource {
+            frames_dir_path: frames_dir_path.to_path_buf(),
+            preview: preparation.preview,
+            dimension_policy: preparation.dimension_policy,
+            dimensions,
+            region,
+            aligner: None,
+        };
+        if let Some(alignment) = &preparation.alignment {
+            let reference_frame = alignment.reference_frame.unwrap_or_default();
+            let reference = source.load_conformed(reference_frame)?;
+            let transforms = read_cached_transforms(frames_dir_path, alignment, reference.dimensions());
+            source.aligner = Some((transforms.len(), FrameAligner::new(*alignment, &reference, transforms)));
+        }
+        Ok(source)
     }
 
-    // Normalize weights so that the total weight sums to 1
-    let image_buffers: Vec<_> = image_buffers
-        .into_par_iter()
-        .map(|(img, mut weight)| {
-            weight /= total_frame_weight;
-            (img, weight)
+    /// Dimensions of the rendered region, `None` if the render reads no frame at all.
+    pub fn region_dimensions(&self) -> Option<(u32, u32)> {
+        self.region.map(|region| (region.width, region.height))
+    }
+
+    fn load_conformed(&self, frame_number: usize) -> Result<RgbaImage, String> {
+        let img = load_frame(&self.frames_dir_path, frame_number, self.preview)?;
+        Ok(match self.dimensions {
+            Some(dimensions) => conform_frame(img, dimensions, self.dimension_policy),
+            None => img,
         })
-        .collect();
+    }
 
-    // Create buffer to hold the final pixel data
-    let final_pixels: Vec<(u8, u8, u8, u8)> = (0..(width * height) as usize)
-        .into_par_iter()
-        .map(|index| {
-            let mut r_accum = 0.0;
-            let mut g_accum = 0.0;
-            let mut b_accum = 0.0;
-            let mut a_accum = 0.0;
-            let mut weight_accum = 0.0;
-
-            // For each pixel in all frames, accumulate weighted colors
-            for (img, frame_weight) in &image_buffers {
-                let x = (index as u32) % width;
-                let y = (index as u32) / width;
-                let pixel = img.get_pixel(x, y);
-                let Rgba(new_data) = *pixel;
-
-                // Normalize alpha to [0,1]
-                let alpha = new_data[3] as f32 / 255.0;
-
-                // Calculate pixel brightness
-                //https://stackoverflow.com/questions/596216/formula-to-determine-perceived-brightness-of-rgb-color
-                let brightness = 0.299 * new_data[0] as f32
-                    + 0.587 * new_data[1] as f32
-                    + 0.114 * new_data[2] as f32;
-                let brightness_norm = brightness / 255.0; // Normalize to [0,1]
-
-                // Adjust pixel weight using brightness
-                let brightness_weight = brightness_norm.powf(4.5); // You can tweak this value
-                let pixel_weight = frame_weight * alpha * brightness_weight;
-
-                // Accumulate weighted color values
-                r_accum += new_data[0] as f32 * pixel_weight;
-                g_accum += new_data[1] as f32 * pixel_weight;
-                b_accum += new_data[2] as f32 * pixel_weight;
-                a_accum += alpha * pixel_weight; // Accumulate alpha
-
-                // Accumulate weight
-                weight_accum += pixel_weight;
+    /// Decodes a whole frame, fitted to the reference dimensions and aligned.
+    pub fn load(&self, frame_number: usize) -> Result<RgbaImage, String> {
+        let img = self.load_conformed(frame_number)?;
+        Ok(match &self.aligner {
+            Some((_, aligner)) => aligner.align(frame_number, img),
+            None => img,
+        })
+    }
+
+    /// Decodes the given rows of the rendered region of a frame, counted from the top of the region.
+    pub fn load_region(&self, frame_number: usize, rows: Range<u32>) -> Result<RgbaImage, String> {
+        let Some(region) = self.region else {
+            return self.load(frame_number);
+        };
+        if Some((region.width, rows.len() as u32)) == self.dimensions {
+            return self.load(frame_number);
+        }
+        let columns = region.x..region.x + region.width;
+        let frame_rows = region.y + rows.start..region.y + rows.end;
+        // Only unmodified frames can be read partially, aligned or conformed frames need all of their pixels
+        let read_partially = self.aligner.is_none()
+            && !self.preview
+            && self.dimension_policy == DimensionPolicy::Reject;
+        if read_partially {
+            let frame_path = frame_path(&self.frames_dir_path, frame_number, false);
+            if let Some(img) = read_png_region(&frame_path, columns.clone(), frame_rows.clone())? {
+                return Ok(img);
             }
+        }
+        Ok(crop_region_of(&self.load(frame_number)?, columns, frame_rows))
+    }
+
+    /// Checks that the rows of every frame can be decoded partially, which tiled renders need to
+    /// bound their memory. Only the headers of the frames are read.
+    pub fn check_partial_reads(&self, frame_numbers: &[usize]) -> Result<(), RenderError> {
+        for frame_number in frame_numbers {
+            if !png_readable_by_rows(&frame_path(&self.frames_dir_path, *frame_number, false))? {
+                return Err(RenderError::InvalidRequest(format!(
+                    "Frame {} is an interlaced or 16-bit PNG and cannot be rendered in tiles",
+                    frame_number
+                )));
+            }
+        }
+        Ok(())
+    }
 
-            if weight_accum > 0.0 {
-                let r = (r_accum / weight_accum).min(255.0);
-                let g = (g_accum / weight_accum).min(255.0);
-                let b = (b_accum / weight_accum).min(255.0);
-                let a = ((a_accum / weight_accum) * 255.0).min(255.0);
-                (r as u8, g as u8, b as u8, a as u8)
-            } else {
-                (0, 0, 0, 0)
+    /// Decodes the rendered region of a frame.
+    pub fn load_rendered(&self, frame_number: usize) -> Result<RgbaImage, String> {
+        let rows = self.region.map_or(0, |region| region.height);
+        self.load_region(frame_number, 0..rows)
+    }
+
+    /// Loads a project mask for the frames and cuts it to the rendered region. The mask covers
+    /// the whole frame, so it is cut after feathering its edges.
+    fn load_mask(&self, mask: &MaskSettings, frame_dimensions: (u32, u32)) -> Result<BlendMask, String> {
+        let mask_path = mask_path(&self.frames_dir_path, &mask.name).map_err(|e| e.to_string())?;
+        let blend_mask = load_blend_mask(&mask_path, self.dimensions.unwrap_or(frame_dimensions), mask)?;
+        Ok(match self.region {
+            Some(region) if Some((region.width, region.height)) != self.dimensions => {
+                imageops::crop_imm(&blend_mask, region.x, region.y, region.width, region.height).to_image()
             }
+            _ => blend_mask,
         })
-        .collect();
+    }
 
-    // Sequentially write the accumulated pixels into the final image
-    for (index, (r, g, b, a)) in final_pixels.into_iter().enumerate() {
-        let x = (index as u32) % width;
-        let y = (index as u32) / width;
-        long_exposure_img.put_pixel(x, y, Rgba([r, g, b, a]));
+    /// Caches the alignment transforms that were estimated while rendering.
+    pub fn store_alignment(&self) {
+        if let Some((cached_count, aligner)) = &self.aligner {
+            let transforms = aligner.transforms();
+            if transforms.len() != *cached_count {
+                write_cached_transforms(
+                    &self.frames_dir_path,
+                    aligner.settings(),
+                    aligner.dimensions(),
+                    transforms,
+                );
+            }
+        }
     }
+}
 
-    #[cfg(debug)]
-    {
-        let image_calculation_time = Utc::now();
-        debug!(
-            "File Reading = {}",
-            (file_processing_end_time - start_time).num_milliseconds()
-        );
-        debug!(
-            "Long Exposure Image Calculations= {}",
-            (image_calculation_time - file_processing_end_time).num_milliseconds()
-        );
-        debug!(
-            "Total Time = {}",
-            (image_calculation_time - start_time).num_milliseconds()
+/// The frames and the mask `finish_render` composites onto a blended image. They are loaded once
+/// per render, so every frame of an animation reuses them.
+pub struct FinishingPlates {
+    /// The key frame and the feathered blend mask of the mask of the request.
+    masked: Option<(RgbaImage, BlendMask)>,
+    frozen_frame: Option<RgbaImage>,
+}
+
+impl FinishingPlates {
+    /// Loads the key frame and mask and the frozen frame of a request, prepared like the blended
+    /// frames and with the dimensions of its blended images.
+    pub fn load(
+        request: &CreateLongExposureImageRequest,
+        source: &FrameSource,
+        dimensions: (u32, u32),
+    ) -> Result<Self, String> {
+        let load_rendered = |frame_number| source.load_rendered(frame_number);
+        let masked = match &request.mask {
+            Some(mask) => {
+                let key_frame_number = mask
+                    .key_frame
+                    .or_else(|| first_selected_frame(&request.frames_to_include))
+                    .unwrap_or_default();
+                // The key frame is prepared like the blended frames, so it is aligned with them
+                let key_frame = load_matching_frame(load_rendered, key_frame_number, dimensions)?;
+                let blend_mask = source.load_mask(mask, key_frame.dimensions())?;
+                Some((key_frame, blend_mask))
+            }
+            None => None,
+        };
+        let frozen_frame = match frozen_frame(&request.frames_to_include).map_err(|e| e.to_string())? {
+            Some(frozen_frame_number) => Some(load_matching_frame(load_rendered, frozen_frame_number, dimensions)?),
+            None => None,
+        };
+        Ok(FinishingPlates { masked, frozen_frame })
+    }
+}
+
+/**
+
+Everything that happens to a blended image before it is encoded: the mask and the frozen frame
+are composited, then the image is rotated and the adjustments are applied. The subject of the
+frozen frame is found by its difference to the blended image, so only its mask is computed for
+every image.
+# Arguments
+- `long_exposure_img`: The blended linear light image of the rendered region.
+- `request`: The render request with its frame selection already expanded.
+- `plates`: The key frame, mask and frozen frame of the request, see `FinishingPlates::load`.
+# Returns
+- The final linear light image.
+
+ */
+pub fn finish_render(
+    mut long_exposure_img: Rgba32FImage,
+    request: &CreateLongExposureImageRequest,
+    plates: &FinishingPlates,
+) -> Rgba32FImage {
+    if let Some((key_frame, blend_mask)) = &plates.masked {
+        composite_with_key_frame(&mut long_exposure_img, key_frame, blend_mask);
+    }
+
+    if let Some(frozen_frame) = &plates.frozen_frame {
+        let freeze_frame = &request.freeze_frame;
+        let subject_mask = feather_mask(
+            difference_mask(&long_exposure_img, frozen_frame, freeze_frame.threshold),
+            freeze_frame.feather,
         );
+        composite_with_key_frame(&mut long_exposure_img, frozen_frame, &subject_mask);
     }
 
-    let long_exposure_image_file_path = utils::generate_timestamped_path(
-        &frames_dir_path.join(".."),
-        "long_exposure_image",
-        "png",
-    );
+    let mut long_exposure_img = request.rotation.apply(long_exposure_img);
+    apply_adjustments(&mut long_exposure_img, &request.adjustments);
     long_exposure_img
-        .save(&long_exposure_image_file_path)
-        .map_err(|e| e.to_string())?;
+}
+
+/// Every frame a render decodes, starting with the first selected frame whose dimensions all
+/// others have to match.
+pub fn frames_read_by(request: &CreateLongExposureImageRequest, preparation: &FramePreparation) -> Vec<usize> {
+    let mut frame_numbers: Vec<usize> = request
+        .frames_to_include
+        .iter()
+        .filter(|frame| frame.frame_weight != 0.0 || frame.freeze)
+        .map(|frame| frame.frame_number)
+        .collect();
+    frame_numbers.extend(preparation.alignment.and_then(|alignment| alignment.reference_frame));
+    frame_numbers.extend(request.mask.as_ref().and_then(|mask| mask.key_frame));
+    frame_numbers
+}
+
+/// Fills in the reference frame of the alignment, which defaults to the first selected frame.
+/// Without a selected frame there is nothing to align.
+fn resolve_alignment(
+    alignment: Option<AlignmentSettings>,
+    frames_data: &[FrameData],
+) -> Option<AlignmentSettings> {
+    let mut alignment = alignment?;
+    alignment.reference_frame = alignment
+        .reference_frame
+        .or_else(|| first_selected_frame(frames_data));
+    alignment.reference_frame.map(|_| alignment)
+}
+
+/// The frame marked with `freeze`, if any.
+fn frozen_frame(frames_data: &[FrameData]) -> Result<Option<usize>, RenderError> {
+    let mut frozen_frames = frames_data.iter().filter(|frame| frame.freeze);
+    let frozen_frame = frozen_frames.next().map(|frame| frame.frame_number);
+    if frozen_frames.next().is_some() {
+        return Err(RenderError::InvalidRequest("Only one frame can be frozen".to_string()));
+    }
+    Ok(frozen_frame)
+}
+
+/// Loads a single frame that is composited with a render of the given dimensions.
+fn load_matching_frame<L>(
+    load_frame: L,
+    frame_number: usize,
+    dimensions: (u32, u32),
+) -> Result<RgbaImage, String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String>,
+{
+    let img = load_frame(frame_number)?;
+    if img.dimensions() != dimensions {
+        return Err(format!(
+            "Frame {} has dimensions {:?}, expected {:?}",
+            frame_number,
+            img.dimensions(),
+            dimensions
+        ));
+    }
+    Ok(img)
+}
+
+fn first_selected_frame(frames_data: &[FrameData]) -> Option<usize> {
+    frames_data
+        .iter()
+        .find(|frame| frame.frame_weight != 0.0)
+        .map(|frame| frame.frame_number)
+}
+
+/// Removes the files and directories older than `max_age` from a directory of a project that only
+/// holds recent renders (e.g. the previews), creating it if needed. Files of renders that are still
+/// running or were just served stay.
+pub fn prune_output_dir(output_dir_path: &Path, max_age: Duration) -> Result<(), String> {
+    fs::create_dir_all(output_dir_path).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    let entries = fs::read_dir(output_dir_path).map_err(|e| format!("Failed to read output directory: {}", e))?;
+    for entry in entries.flatten() {
+        let expired = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > max_age));
+        if !expired {
+            continue;
+        }
+        let path = entry.path();
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        // Another render may prune the same entry concurrently
+        if let Err(e) = removed {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(format!("Failed to remove previous renders: {}", e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// File of a project mask. The name comes from the request, so it is checked like on upload
+/// before it becomes part of a path.
+fn mask_path(frames_dir_path: &Path, mask_name: &str) -> Result<PathBuf, RenderError> {
+    if !is_valid_mask_name(mask_name) {
+        return Err(RenderError::InvalidRequest(format!(
+            "Invalid mask name '{}', only letters, digits, '-' and '_' are allowed",
+            mask_name
+        )));
+    }
+    let mask_path = frames_dir_path.join("..").join(mask_file_name(mask_name));
+    if !mask_path.is_file() {
+        return Err(RenderError::InvalidRequest(format!("Mask '{}' does not exist", mask_name)));
+    }
+    Ok(mask_path)
+}
+
+/// Number of the last frame extracted from the video of a project, 0 if there is none.
+fn last_frame_number(frames_dir_path: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(frames_dir_path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let frame_number = file_name.to_str()?.strip_prefix("ffout_")?.strip_suffix(".png")?;
+            frame_number.parse::<usize>().ok()
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// File of a frame, or of its thumbnail written during extraction for previews.
+fn frame_path(frames_dir_path: &Path, frame_number: usize, preview: bool) -> PathBuf {
+    let frame_file_name = if preview {
+        format!("ffout_thumbnail_{:04}.webp", frame_number)
+    } else {
+        format!("ffout_{:04}.png", frame_number)
+    };
+    frames_dir_path.join(frame_file_name)
+}
+
+fn load_frame(frames_dir_path: &Path, frame_number: usize, preview: bool) -> Result<RgbaImage, String> {
+    let frame_path = frame_path(frames_dir_path, frame_number, preview);
+    Ok(image::open(&frame_path)
+        .map_err(|e| format!("Failed to open image: {}", e))?
+        .to_rgba8())
+}
+
+/**
+
+Blends the given frames into a single image.
+Streamable blend modes decode a small batch of frames in parallel, add them to an
+`Accumulator` and drop them again. Modes that need all samples of a pixel fall back to
+rendering the image in bands, stroboscopic renders then composite their subjects.
+Time slices place the frames next to each other instead of blending them.
+# Arguments
+- `frames_data`: The frames to include and their weights.
+- `settings`: The blend mode, weighting and color space of the render.
+- `dimensions`: The dimensions all frames share, e.g. the region of the `FrameSource`.
+- `load_frame`: Decodes a frame by its frame number.
+# Returns
+- The blended linear light image with channel values normalized to `[0,1]` and, for modes that
+  reject samples, how many were rejected, or an error message.
+
+ */
+pub fn render_frames<L>(
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    dimensions: (u32, u32),
+    load_frame: L,
+) -> Result<(Rgba32FImage, Option<RejectionStatistics>), String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+{
+    let frames_data = contributing_frames(frames_data)?;
 
-    Ok(
-        convert_image_path_to_serving_url(&long_exposure_image_file_path).await,
-    )
-}
\ No newline at end of file
+    if settings.blend_mode.is_streamable() {
+        let accumulator: Accumulator = accumulate_frames(&frames_data, settings, &load_frame)?;
+        return Ok((accumulator.finish(), None));
+    }
+
+    if let BlendMode::TimeSlice { layout, blend } = settings.blend_mode {
+        let time_slice_img = render_time_slice(
+            &frames_data,
+            layout,
+            blend,
+            settings.color_space,
+            dimensions,
+            0..dimensions.1,
+            load_frame,
+        )?;
+        return Ok((time_slice_img, None));
+    }
+    let (pixel_fn, color_space) = stack_pixel_fn(settings);
+    let (mut long_exposure_img, rejected_samples) =
+        render_in_bands(&frames_data, dimensions, &load_frame, pixel_fn)?;
+    color_space.convert_to_linear(&mut long_exposure_img);
+    composite_stroboscopic(&mut long_exposure_img, settings.blend_mode, &frames_data, &load_frame)?;
+    let rejection = rejection_statistics(settings.blend_mode, rejected_samples, frames_data.len(), dimensions);
+    Ok((long_exposure_img, rejection))
+}
+
+/// Rejected samples of the modes that discard outliers, counted per color channel.
+pub fn rejection_statistics(
+    blend_mode: BlendMode,
+    rejected_samples: usize,
+    frame_count: usize,
+    (width, height): (u32, u32),
+) -> Option<RejectionStatistics> {
+    matches!(blend_mode, BlendMode::SigmaClipping { .. }).then(|| RejectionStatistics {
+        rejected_samples: rejected_samples as u64,
+        total_samples: frame_count as u64 * width as u64 * height as u64 * 3,
+    })
+}
+
+/// Validates the frame selection and drops frames without weight, they do not contribute
+/// in any mode and therefore are not even decoded.
+pub fn contributing_frames(frames_data: &[FrameData]) -> Result<Vec<FrameData>, String> {
+    if frames_data.is_empty() {
+        return Err("No images were chosen".to_string());
+    }
+
+    let total_frame_weight: f32 = frames_data.iter().map(|frame| frame.frame_weight).sum();
+    if total_frame_weight == 0.0 {
+        return Err("Total frame weight cannot be zero".to_string());
+    }
+
+    Ok(frames_data
+        .iter()
+        .filter(|frame| frame.frame_weight != 0.0)
+        .cloned()
+        .collect())
+}
+
+/// Creates an `Accumulator` sized like the first frame and adds all frames to it.
+/// `frames_data` must not be empty, see `contributing_frames`.
+pub fn accumulate_frames<T, L>(
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    load_frame: L,
+) -> Result<Accumulator<T>, String>
+where
+    T: AccumulatorValue,
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+{
+    let first_frame = load_frame(frames_data[0].frame_number)?;
+    let (width, height) = first_frame.dimensions();
+    let factors = frame_factors(settings.blend_mode, frames_data);
+
+    let mut accumulator = Accumulator::new(width, height, settings.clone());
+    accumulator.add_frame(&first_frame, factors[0]);
+    drop(first_frame);
+
+    let frame_numbers: Vec<usize> = frames_data[1..].iter().map(|frame| frame.frame_number).collect();
+    stream_frames(&frame_numbers, (width, height), load_frame, |index, img| {
+        accumulator.add_frame(img, factors[index + 1]);
+    })?;
+
+    Ok(accumulator)
+}
+
+/**
+
+Decodes the given frames one batch at a time and hands them to `consume` in order.
+A batch holds one frame per thread, so the memory used is independent of the number of frames.
+# Arguments
+- `frame_numbers`: The frames to decode.
+- `dimensions`: The dimensions every frame is required to have.
+- `load_frame`: Decodes a frame by its frame number.
+- `consume`: Called with the index into `frame_numbers` and the decoded frame.
+
+ */
+pub fn stream_frames<L, C>(
+    frame_numbers: &[usize],
+    dimensions: (u32, u32),
+    load_frame: L,
+    mut consume: C,
+) -> Result<(), String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+    C: FnMut(usize, &RgbaImage),
+{
+    let batch_size = rayon::current_num_threads();
+    for (batch_index, frame_batch) in frame_numbers.chunks(batch_size).enumerate() {
+        let decoded: Vec<RgbaImage> = frame_batch
+            .par_iter()
+            .map(|frame_number| {
+                let img = load_frame(*frame_number)?;
+                if img.dimensions() != dimensions {
+                    return Err(format!(
+                        "Frame {} has dimensions {:?}, expected {:?}",
+                        frame_number,
+                        img.dimensions(),
+                        dimensions
+                    ));
+                }
+                Ok(img)
+            })
+            .collect::<Result<_, String>>()?;
+
+        for (offset, img) in decoded.iter().enumerate() {
+            consume(batch_index * batch_size + offset, img);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+    use crate::core::output::{encode_rgba8, to_rgba8};
+    use crate::core::test_support::{frames_data, linear_settings};
+    use crate::models::{
+        BlendMode, ClippingCenter, ColorKey, LumaCoefficients, TrailFade, WeightingCurve, WeightingSettings,
+    };
+
+    /// Blends already decoded frames, using their index as frame number.
+    fn blend_frames(
+        image_buffers: &[(RgbaImage, f32)],
+        blend_mode: BlendMode,
+        weighting: &WeightingSettings,
+    ) -> Result<RgbaImage, String> {
+        blend_frames_in(image_buffers, blend_mode, weighting, BlendColorSpace::Srgb)
+    }
+
+    fn blend_frames_in(
+        image_buffers: &[(RgbaImage, f32)],
+        blend_mode: BlendMode,
+        weighting: &WeightingSettings,
+        color_space: BlendColorSpace,
+    ) -> Result<RgbaImage, String> {
+        let settings = BlendSettings {
+            blend_mode,
+            weighting: weighting.clone(),
+            color_space,
+        };
+        let frames_data = frames_data(image_buffers.iter().map(|(_, frame_weight)| *frame_weight));
+
+        let dimensions = image_buffers[0].0.dimensions();
+        let (long_exposure_img, _) = render_frames(&frames_data, &settings, dimensions, |frame_number| {
+            Ok(image_buffers[frame_number].0.clone())
+        })?;
+
+        Ok(encode_rgba8(long_exposure_img, &settings))
+    }
+
+    fn solid_frame(rgba: [u8; 4], weight: f32) -> (RgbaImage, f32) {
+        (ImageBuffer::from_pixel(2, 2, Rgba(rgba)), weight)
+    }
+
+    fn blended_pixel(frames: &[(RgbaImage, f32)], blend_mode: BlendMode) -> [u8; 4] {
+        blend_frames(frames, blend_mode, &WeightingSettings::default())
+            .unwrap()
+            .get_pixel(1, 1)
+            .0
+    }
+
+    #[test]
+    fn weighted_mean_of_identical_frames_is_unchanged() {
+        let frames = vec![
+            solid_frame([200, 100, 50, 255], 1.0),
+            solid_frame([200, 100, 50, 255], 3.0),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::WeightedMean), [200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn weighted_mean_respects_frame_weight() {
+        let frames = vec![
+            solid_frame([100, 100, 100, 255], 1.0),
+            solid_frame([100, 100, 100, 255], 0.0),
+            solid_frame([200, 200, 200, 255], 0.0),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::WeightedMean), [100, 100, 100, 255]);
+    }
+
+    #[test]
+    fn lighten_and_darken_pick_extremes() {
+        let frames = vec![
+            solid_frame([10, 200, 30, 255], 1.0),
+            solid_frame([250, 20, 40, 255], 1.0),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::Lighten), [250, 200, 40, 255]);
+        assert_eq!(blended_pixel(&frames, BlendMode::Darken), [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn lighten_scales_frames_by_relative_weight() {
+        let frames = vec![
+            solid_frame([100, 100, 100, 255], 1.0),
+            solid_frame([255, 255, 255, 255], 0.2),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::Lighten), [100, 100, 100, 255]);
+    }
+
+    #[test]
+    fn light_trails_fade_with_frame_distance() {
+        let frames = vec![
+            solid_frame([200, 200, 200, 255], 1.0),
+            solid_frame([0, 0, 0, 255], 1.0),
+            solid_frame([0, 0, 0, 255], 1.0),
+        ];
+        let trails = |fade| BlendMode::LightTrails {
+            half_life: 1.0,
+            fade,
+        };
+        // The first frame is two half-lives away from the last one
+        assert_eq!(blended_pixel(&frames, trails(TrailFade::TowardFirst)), [50, 50, 50, 255]);
+        assert_eq!(blended_pixel(&frames, trails(TrailFade::TowardLast)), [200, 200, 200, 255]);
+    }
+
+    #[test]
+    fn sigma_clipping_rejects_outlier_frame() {
+        let frames = frames_data([1.0; 5]);
+        let load = |frame_number| {
+            let value = if frame_number == 2 { 250 } else { 50 };
+            Ok(ImageBuffer::from_pixel(2, 2, Rgba([value, value, value, 255])))
+        };
+
+        for (kappa, center) in [(1.5, ClippingCenter::Mean), (3.0, ClippingCenter::Median)] {
+            let settings = linear_settings(BlendMode::SigmaClipping {
+                kappa,
+                iterations: 3,
+                center,
+            });
+            let (long_exposure_img, rejection) = render_frames(&frames, &settings, (2, 2), load).unwrap();
+            assert_eq!(to_rgba8(long_exposure_img).get_pixel(1, 1).0, [50, 50, 50, 255]);
+            assert_eq!(
+                rejection,
+                Some(RejectionStatistics {
+                    rejected_samples: 12,
+                    total_samples: 60,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn sigma_clipping_keeps_samples_close_to_an_exact_median() {
+        let frames = frames_data([1.0; 5]);
+        // Most frames agree exactly, so the median absolute deviation is zero
+        let load = |frame_number| {
+            let value = [50, 50, 50, 51, 52][frame_number];
+            Ok(ImageBuffer::from_pixel(2, 2, Rgba([value, value, value, 255])))
+        };
+        let settings = linear_settings(BlendMode::SigmaClipping {
+            kappa: 3.0,
+            iterations: 3,
+            center: ClippingCenter::Median,
+        });
+        let (_, rejection) = render_frames(&frames, &settings, (2, 2), load).unwrap();
+        assert_eq!(rejection.map(|rejection| rejection.rejected_samples), Some(0));
+    }
+
+    #[test]
+    fn median_ignores_outlier_frame() {
+        let frames = vec![
+            solid_frame([50, 50, 50, 255], 1.0),
+            solid_frame([52, 52, 52, 255], 1.0),
+            solid_frame([255, 0, 255, 255], 1.0),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::Median), [52, 50, 52, 255]);
+    }
+
+    #[test]
+    fn median_honours_heavier_frames() {
+        let frames = vec![
+            solid_frame([10, 10, 10, 255], 3.0),
+            solid_frame([200, 200, 200, 255], 1.0),
+            solid_frame([220, 220, 220, 255], 1.0),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::Median), [10, 10, 10, 255]);
+    }
+
+    #[test]
+    fn screen_brightens_and_additive_saturates() {
+        let frames = vec![
+            solid_frame([128, 0, 255, 255], 1.0),
+            solid_frame([128, 0, 0, 255], 1.0),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::Screen), [192, 0, 255, 255]);
+        assert_eq!(blended_pixel(&frames, BlendMode::Additive), [255, 0, 255, 255]);
+    }
+
+    #[test]
+    fn boosted_samples_stay_in_range_for_screen_and_darken() {
+        // Both frames are boosted four times, so their opacity is above 1
+        let frames = vec![
+            solid_frame([250, 10, 30, 255], 1.0),
+            solid_frame([250, 10, 30, 255], 1.0),
+        ];
+        let weighting = WeightingSettings {
+            curve: WeightingCurve::None,
+            luma: LumaCoefficients::Rec601,
+            color_keys: vec![ColorKey {
+                hue_start: 340.0,
+                hue_end: 20.0,
+                min_saturation: 0.4,
+                max_saturation: 1.0,
+                boost: 4.0,
+                softness: 0.0,
+            }],
+        };
+        let pixel = |blend_mode| blend_frames(&frames, blend_mode, &weighting).unwrap().get_pixel(1, 1).0;
+        assert_eq!(pixel(BlendMode::Screen)[0], 255);
+        assert_eq!(pixel(BlendMode::Darken)[1], 0);
+    }
+
+    #[test]
+    fn additive_uses_weight_as_gain() {
+        let frames = vec![
+            solid_frame([100, 100, 100, 255], 0.5),
+            solid_frame([100, 100, 100, 255], 0.5),
+        ];
+        assert_eq!(blended_pixel(&frames, BlendMode::Additive), [100, 100, 100, 255]);
+    }
+
+    #[test]
+    fn unweighted_curve_gives_plain_average() {
+        let frames = vec![
+            solid_frame([0, 0, 0, 255], 1.0),
+            solid_frame([200, 200, 200, 255], 1.0),
+        ];
+        let weighting = WeightingSettings {
+            curve: WeightingCurve::None,
+            luma: LumaCoefficients::Rec709,
+            color_keys: Vec::new(),
+        };
+        let img = blend_frames(&frames, BlendMode::WeightedMean, &weighting).unwrap();
+        assert_eq!(img.get_pixel(0, 0).0, [100, 100, 100, 255]);
+        // The default power curve ignores black pixels entirely
+        assert_eq!(blended_pixel(&frames, BlendMode::WeightedMean), [200, 200, 200, 255]);
+    }
+
+    #[test]
+    fn linear_blending_keeps_highlights_bright() {
+        let frames = vec![
+            solid_frame([0, 0, 0, 255], 1.0),
+            solid_frame([255, 255, 255, 255], 1.0),
+        ];
+        let weighting = WeightingSettings {
+            curve: WeightingCurve::None,
+            luma: LumaCoefficients::Rec601,
+            color_keys: Vec::new(),
+        };
+        let linear =
+            blend_frames_in(&frames, BlendMode::WeightedMean, &weighting, BlendColorSpace::Linear)
+                .unwrap();
+        assert_eq!(linear.get_pixel(0, 0).0, [188, 188, 188, 255]);
+        // Blending in sRGB truncates like the renders from before linear blending
+        let legacy = blend_frames(&frames, BlendMode::WeightedMean, &weighting).unwrap();
+        assert_eq!(legacy.get_pixel(0, 0).0, [127, 127, 127, 255]);
+    }
+
+    /// Prepares a request given as JSON for a project without any files.
+    fn prepare(request: serde_json::Value) -> Result<CreateLongExposureImageRequest, RenderError> {
+        let mut request: CreateLongExposureImageRequest = serde_json::from_value(request).unwrap();
+        let frames_dir_path = std::env::temp_dir().join("no_project").join("frames");
+        prepare_request(&frames_dir_path, &mut request, BlendColorSpace::Linear).map(|_| request)
+    }
+
+    #[test]
+    fn invalid_or_missing_masks_are_rejected_before_rendering() {
+        for name in ["../../etc/passwd", "", "missing"] {
+            let request = serde_json::json!({
+                "frames_to_include": [{"frame_number": 1, "frame_weight": 1.0}],
+                "mask": {"name": name},
+            });
+            assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn pruning_keeps_recent_renders() {
+        let previews_dir = std::env::temp_dir().join(format!("prune_previews_test_{}", std::process::id()));
+        fs::create_dir_all(&previews_dir).unwrap();
+        let old_preview = previews_dir.join("preview_old.png");
+        let recent_preview = previews_dir.join("preview_recent.png");
+        fs::write(&old_preview, b"old").unwrap();
+        fs::write(&recent_preview, b"recent").unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - Duration::from_secs(60 * 60);
+        fs::File::options().write(true).open(&old_preview).unwrap().set_modified(an_hour_ago).unwrap();
+        // Batches of variants are pruned as a whole directory
+        let old_batch = previews_dir.join("old_batch");
+        fs::create_dir(&old_batch).unwrap();
+        fs::write(old_batch.join("variant_1.png"), b"old").unwrap();
+        fs::File::open(&old_batch).unwrap().set_modified(an_hour_ago).unwrap();
+
+        prune_output_dir(&previews_dir, PREVIEW_RETENTION).unwrap();
+
+        assert!(!old_preview.exists());
+        assert!(!old_batch.exists());
+        assert!(recent_preview.exists());
+        fs::remove_dir_all(&previews_dir).unwrap();
+    }
+
+    #[test]
+    fn tiles_of_modified_frames_are_rejected_before_rendering() {
+        let request = serde_json::json!({
+            "frames_to_include": [{"frame_number": 1, "frame_weight": 1.0}],
+            "preview": true,
+            "tile_rows": 256,
+        });
+        assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn empty_or_stroboscopic_tiles_are_rejected_before_rendering() {
+        let requests = [
+            serde_json::json!({
+                "frames_to_include": [{"frame_number": 1, "frame_weight": 1.0}],
+                "tile_rows": 0,
+            }),
+            serde_json::json!({
+                "frames_to_include": [{"frame_number": 1, "frame_weight": 1.0}],
+                "blend_mode": {"stroboscopic": {}},
+                "tile_rows": 256,
+            }),
+        ];
+        for request in requests {
+            assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))));
+        }
+    }
+
+    #[test]
+    fn build_up_of_stacked_modes_is_rejected_before_rendering() {
+        let request = serde_json::json!({
+            "frames_to_include": [{"frame_number": 1, "frame_weight": 1.0}],
+            "blend_mode": "median",
+            "animation": {"type": "build_up"},
+        });
+        assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn time_slices_without_width_are_rejected_before_rendering() {
+        for frame_weight in [0.0, -1.0] {
+            let request = serde_json::json!({
+                "frames_to_include": [
+                    {"frame_number": 1, "frame_weight": 1.0},
+                    {"frame_number": 2, "frame_weight": frame_weight},
+                ],
+                "blend_mode": {"time_slice": {"layout": {"type": "vertical"}}},
+            });
+            assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))), "{}", frame_weight);
+        }
+    }
+
+    #[test]
+    fn oversized_outputs_are_rejected_before_rendering() {
+        let request = serde_json::json!({
+            "frames_to_include": [{"frame_number": 1, "frame_weight": 1.0}],
+            "output": {"width": 100, "height": MAX_OUTPUT_DIMENSION + 1},
+        });
+        assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn cropped_render_equals_the_crop_of_the_full_render() {
+        let project_dir = std::env::temp_dir().join(format!("crop_render_test_{}", std::process::id()));
+        let frames_dir_path = project_dir.join("frames");
+        fs::create_dir_all(&frames_dir_path).unwrap();
+        for frame_number in 1..=3u32 {
+            let frame: RgbaImage = ImageBuffer::from_fn(12, 10, |x, y| {
+                let value = ((frame_number * 71 + x * 19 + y * 37) % 256) as u8;
+                Rgba([value, 255 - value, value / 2, 255])
+            });
+            frame.save(frames_dir_path.join(format!("ffout_{:04}.png", frame_number))).unwrap();
+        }
+        // The left half of the frame shows the blend, the right half the key frame
+        let mask = image::GrayImage::from_fn(12, 10, |x, _| image::Luma([if x < 6 { 255 } else { 0 }]));
+        mask.save(project_dir.join(mask_file_name("left"))).unwrap();
+
+        let render = |crop: serde_json::Value| {
+            let mut request: CreateLongExposureImageRequest = serde_json::from_value(serde_json::json!({
+                "frame_ranges": [{"start": 1, "end": 3}],
+                "mask": {"name": "left", "key_frame": 3, "feather": 2},
+                "crop": crop,
+            }))
+            .unwrap();
+            let (_, settings, preparation) =
+                prepare_request(&frames_dir_path, &mut request, BlendColorSpace::Linear).unwrap();
+            let source = FrameSource::new(&frames_dir_path, frames_read_by(&request, &preparation), &preparation)
+                .unwrap();
+            let dimensions = source.region_dimensions().unwrap();
+            let (img, _) = render_frames(&request.frames_to_include, &settings, dimensions, |frame_number| {
+                source.load_rendered(frame_number)
+            })
+            .unwrap();
+            let plates = FinishingPlates::load(&request, &source, img.dimensions()).unwrap();
+            finish_render(img, &request, &plates)
+        };
+        let full = render(serde_json::Value::Null);
+        let cropped = render(serde_json::json!({"x": 3, "y": 2, "width": 6, "height": 5}));
+        fs::remove_dir_all(&project_dir).unwrap();
+
+        let expected = imageops::crop_imm(&full, 3, 2, 6, 5).to_image();
+        assert_eq!(cropped.as_raw(), expected.as_raw());
+    }
+
+    #[test]
+    fn several_frozen_frames_are_rejected_before_rendering() {
+        let request = serde_json::json!({
+            "frames_to_include": [
+                {"frame_number": 1, "frame_weight": 1.0, "freeze": true},
+                {"frame_number": 2, "frame_weight": 1.0, "freeze": true},
+            ],
+        });
+        assert!(matches!(prepare(request), Err(RenderError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn zero_total_weight_is_rejected() {
+        let frames = vec![solid_frame([1, 2, 3, 255], 0.0)];
+        assert!(blend_frames(&frames, BlendMode::Lighten, &WeightingSettings::default()).is_err());
+    }
+}
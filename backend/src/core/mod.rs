@@ -1 +1,20 @@
+pub mod accumulator;
+pub mod adjustments;
+pub mod alignment;
+pub mod animation;
+pub mod batch;
+pub mod color;
+pub mod compositing;
+pub mod contact_sheet;
+pub mod dimensions;
+pub mod incremental;
 pub mod long_exposure_image_logic;
+pub mod output;
+pub mod sample_stack;
+pub mod selection;
+pub mod stroboscopic;
+#[cfg(test)]
+pub mod test_support;
+pub mod tiling;
+pub mod time_slice;
+pub mod weighting;
@@ -0,0 +1,484 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{
+    DynamicImage, ExtendedColorType, ImageBuffer, ImageEncoder, Pixel, Rgba, Rgba32FImage,
+    RgbaImage,
+};
+use rayon::prelude::*;
+
+use crate::core::accumulator::BlendSettings;
+use crate::core::color::{encode_srgb, linear_to_srgb8, linear_to_srgb8_truncated, TRUNCATION_TOLERANCE};
+use crate::models::{
+    CreateLongExposureImageRequest, LumaCoefficients, OutputFormat, OutputSettings, ToneMapping,
+};
+use crate::utils;
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png | OutputFormat::Png16 => "png",
+            OutputFormat::Tiff16 => "tif",
+            OutputFormat::Exr => "exr",
+            OutputFormat::Jpeg { .. } => "jpg",
+            OutputFormat::Webp { .. } => "webp",
+            OutputFormat::Avif { .. } => "avif",
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Applies the operator to the color channels of a linear light image, alpha stays untouched.
+    pub fn apply(&self, img: &mut Rgba32FImage) {
+        let tone_mapping = *self;
+        if tone_mapping == ToneMapping::Clip {
+            return;
+        }
+
+        img.par_chunks_mut(4).for_each(|pixel| match tone_mapping {
+            ToneMapping::Clip => {}
+            ToneMapping::Exposure { stops } => {
+                let scale = 2f32.powf(stops);
+                for channel in &mut pixel[..3] {
+                    *channel *= scale;
+                }
+            }
+            ToneMapping::Reinhard { white_point } => {
+                // Scaling all channels by the mapped luminance keeps the hue
+                let luminance = LumaCoefficients::Rec709.luma(pixel[0], pixel[1], pixel[2]);
+                if luminance <= 0.0 {
+                    return;
+                }
+                let mapped = match white_point {
+                    Some(white) if white > 0.0 => {
+                        luminance * (1.0 + luminance / (white * white)) / (1.0 + luminance)
+                    }
+                    _ => luminance / (1.0 + luminance),
+                };
+                for channel in &mut pixel[..3] {
+                    *channel *= mapped / luminance;
+                }
+            }
+            ToneMapping::Filmic => {
+                // https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+                for channel in &mut pixel[..3] {
+                    let x = channel.max(0.0);
+                    *channel = ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0);
+                }
+            }
+        });
+    }
+}
+
+/// Largest width or height an image is scaled to, larger `width` or `height` requests are rejected.
+pub const MAX_OUTPUT_DIMENSION: u32 = 16384;
+
+/**
+
+Tone maps and encodes a rendered image and writes it into `output_dir` with a timestamped name.
+# Arguments
+- `long_exposure_img`: The rendered linear light image.
+- `output_settings`: The file format, tone mapping and maximum size to use.
+- `settings`: The settings the image was blended with, they decide how 8-bit output is quantized.
+- `output_dir`: The directory the image is written to.
+- `base_name`: The file name before the timestamp.
+# Returns
+- The path of the written image or an error message.
+
+ */
+pub fn save_long_exposure_image(
+    mut long_exposure_img: Rgba32FImage,
+    output_settings: &OutputSettings,
+    settings: &BlendSettings,
+    output_dir: &Path,
+    base_name: &str,
+) -> Result<PathBuf, String> {
+    long_exposure_img = tone_map_and_resize(long_exposure_img, output_settings);
+
+    let long_exposure_image_file_path = utils::generate_timestamped_path(
+        output_dir,
+        base_name,
+        output_settings.format.extension(),
+    );
+
+    let encoded_img = match output_settings.format {
+        OutputFormat::Png16 | OutputFormat::Tiff16 => {
+            DynamicImage::ImageRgba16(to_rgba16(long_exposure_img))
+        }
+        // EXR stores linear light, values above 1 are preserved
+        OutputFormat::Exr => DynamicImage::ImageRgba32F(long_exposure_img),
+        _ => DynamicImage::ImageRgba8(encode_rgba8(long_exposure_img, settings)),
+    };
+    write_image(&encoded_img, output_settings.format, &long_exposure_image_file_path)?;
+    if let OutputFormat::Tiff16 | OutputFormat::Exr = output_settings.format {
+        write_browser_preview(encoded_img, settings, &long_exposure_image_file_path)?;
+    }
+
+    Ok(long_exposure_image_file_path)
+}
+
+/// Browsers can not show TIFF or EXR, so those renders get an 8-bit PNG next to them that is
+/// e.g. used as project thumbnail. It is named like the render with `.preview.png` as extension.
+fn write_browser_preview(
+    encoded_img: DynamicImage,
+    settings: &BlendSettings,
+    image_path: &Path,
+) -> Result<PathBuf, String> {
+    let preview_path = image_path.with_extension("preview.png");
+    let preview = match encoded_img {
+        // Linear light values are encoded and clipped like an 8-bit render
+        DynamicImage::ImageRgba32F(img) => encode_rgba8(img, settings),
+        // 16-bit sRGB values only lose their precision
+        img => img.to_rgba8(),
+    };
+    preview
+        .save(&preview_path)
+        .map_err(|e| format!("Failed to save browser preview: {}", e))?;
+    Ok(preview_path)
+}
+
+/// Applies the tone mapping and the target size of the output settings, everything that happens
+/// to an image before it is encoded.
+pub fn tone_map_and_resize(mut long_exposure_img: Rgba32FImage, output_settings: &OutputSettings) -> Rgba32FImage {
+    output_settings.tone_mapping.apply(&mut long_exposure_img);
+    long_exposure_img = resize_to(long_exposure_img, output_settings.width, output_settings.height);
+    if let Some(max_dimension) = output_settings.max_dimension {
+        long_exposure_img = limit_dimensions(long_exposure_img, max_dimension);
+    }
+    long_exposure_img
+}
+
+/**
+
+Records the request an image was rendered with in a JSON file next to it, with the same name
+as the image. Sending the recorded request again renders the same image as long as the frames
+and masks of the project are unchanged, masks are only recorded by their name.
+# Arguments
+- `image_path`: The path of the written image.
+- `request`: The request with the color space it was rendered in.
+# Returns
+- The path of the record or an error message.
+
+ */
+pub fn write_render_record(
+    image_path: &Path,
+    request: &CreateLongExposureImageRequest,
+) -> Result<PathBuf, String> {
+    let record_path = image_path.with_extension("json");
+    let record = serde_json::to_string_pretty(request)
+        .map_err(|e| format!("Failed to serialize render record: {}", e))?;
+    std::fs::write(&record_path, record)
+        .map_err(|e| format!("Failed to write render record: {}", e))?;
+    Ok(record_path)
+}
+
+/// Writes an encoded image, using the quality settings of lossy formats.
+fn write_image(img: &DynamicImage, format: OutputFormat, path: &Path) -> Result<(), String> {
+    let to_string = |e: image::ImageError| e.to_string();
+    match format {
+        OutputFormat::Png | OutputFormat::Png16 | OutputFormat::Tiff16 | OutputFormat::Exr => {
+            img.save(path).map_err(to_string)
+        }
+        OutputFormat::Jpeg { quality } => {
+            // JPEG has no alpha channel
+            let rgb = img.to_rgb8();
+            let writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+            JpegEncoder::new_with_quality(writer, quality.clamp(1, 100))
+                .write_image(&rgb, rgb.width(), rgb.height(), ExtendedColorType::Rgb8)
+                .map_err(to_string)
+        }
+        OutputFormat::Webp { lossless: true, .. } => img.save(path).map_err(to_string),
+        OutputFormat::Webp { quality, .. } => {
+            let rgba = img.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(quality.min(100) as f32);
+            std::fs::write(path, &*encoded).map_err(|e| e.to_string())
+        }
+        OutputFormat::Avif { quality, speed } => {
+            let rgba = img.to_rgba8();
+            let writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+            AvifEncoder::new_with_speed_quality(writer, speed.clamp(1, 10), quality.clamp(1, 100))
+                .write_image(&rgba, rgba.width(), rgba.height(), ExtendedColorType::Rgba8)
+                .map_err(to_string)
+        }
+    }
+}
+
+/// Scales the image to the requested output size. With only one side given the aspect ratio is kept.
+fn resize_to(img: Rgba32FImage, width: Option<u32>, height: Option<u32>) -> Rgba32FImage {
+    let Some((new_width, new_height)) = resized_dimensions(img.dimensions(), width, height) else {
+        return img;
+    };
+    if (new_width, new_height) == img.dimensions() {
+        return img;
+    }
+    // Like `limit_dimensions`, resampling happens in linear light
+    imageops::resize(&img, new_width, new_height, FilterType::Lanczos3)
+}
+
+/// Dimensions `resize_to` scales an image to, `None` without a requested size. The side that
+/// follows from the aspect ratio can exceed `MAX_OUTPUT_DIMENSION` for very narrow images, then
+/// both sides are shrunk.
+fn resized_dimensions(
+    (current_width, current_height): (u32, u32),
+    width: Option<u32>,
+    height: Option<u32>,
+) -> Option<(u32, u32)> {
+    let aspect_ratio = current_width as f64 / current_height.max(1) as f64;
+    let (new_width, new_height) = match (width, height) {
+        (Some(width), Some(height)) => (width as f64, height as f64),
+        (Some(width), None) => (width as f64, width as f64 / aspect_ratio),
+        (None, Some(height)) => (height as f64 * aspect_ratio, height as f64),
+        (None, None) => return None,
+    };
+    let scale = (MAX_OUTPUT_DIMENSION as f64 / new_width.max(new_height)).min(1.0);
+    Some((
+        ((new_width * scale).round() as u32).max(1),
+        ((new_height * scale).round() as u32).max(1),
+    ))
+}
+
+/// Downscales the image so that its longer side is at most `max_dimension`, keeping the aspect ratio.
+fn limit_dimensions(img: Rgba32FImage, max_dimension: u32) -> Rgba32FImage {
+    let (width, height) = img.dimensions();
+    let longer_side = width.max(height);
+    if max_dimension == 0 || longer_side <= max_dimension {
+        return img;
+    }
+    let scale = max_dimension as f64 / longer_side as f64;
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    // Resampling happens in linear light, so bright details keep their energy
+    imageops::resize(&img, new_width, new_height, FilterType::Lanczos3)
+}
+
+/// Encodes a linear light image to 8-bit sRGB, clipping values above 1.
+/// Rows are encoded in parallel with a lookup table instead of `powf`.
+pub fn to_rgba8(img: Rgba32FImage) -> RgbaImage {
+    quantize_8bit(img, linear_to_srgb8, |alpha| (alpha * 255.0).round())
+}
+
+/// Encodes a rendered image to 8-bit sRGB, truncating instead of rounding if the settings it was
+/// blended with ask for it, see `BlendSettings::truncates_8bit_output`.
+pub fn encode_rgba8(img: Rgba32FImage, settings: &BlendSettings) -> RgbaImage {
+    if settings.truncates_8bit_output() {
+        quantize_8bit(img, linear_to_srgb8_truncated, |alpha| (alpha * 255.0 + TRUNCATION_TOLERANCE).floor())
+    } else {
+        to_rgba8(img)
+    }
+}
+
+fn quantize_8bit(
+    img: Rgba32FImage,
+    encode_channel: impl Fn(f32) -> u8 + Sync,
+    scale_alpha: impl Fn(f32) -> f32 + Sync,
+) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let row_len = width as usize * 4;
+    let mut encoded: RgbaImage = ImageBuffer::new(width, height);
+    if row_len == 0 {
+        return encoded;
+    }
+    encoded
+        .par_chunks_mut(row_len)
+        .zip(img.as_raw().par_chunks(row_len))
+        .for_each(|(encoded_row, row)| {
+            for (out, pixel) in encoded_row.chunks_exact_mut(4).zip(row.chunks_exact(4)) {
+                for (channel, value) in out[..3].iter_mut().zip(pixel) {
+                    *channel = encode_channel(*value);
+                }
+                out[3] = scale_alpha(pixel[3]).clamp(0.0, 255.0) as u8;
+            }
+        });
+    encoded
+}
+
+/// Encodes a linear light image to 16-bit sRGB, clipping values above 1.
+pub fn to_rgba16(img: Rgba32FImage) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+    quantize(img, u16::MAX as f32, |value| value as u16)
+}
+
+fn quantize<T>(
+    mut img: Rgba32FImage,
+    max: f32,
+    convert: impl Fn(f32) -> T + Sync,
+) -> ImageBuffer<Rgba<T>, Vec<T>>
+where
+    T: Send,
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    encode_srgb(&mut img);
+    let pixels = img
+        .as_raw()
+        .par_iter()
+        .map(|value| convert((value * max).round().clamp(0.0, max)))
+        .collect();
+    ImageBuffer::from_raw(img.width(), img.height(), pixels)
+        .expect("buffer has the dimensions of the source image")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    use crate::core::color::linear_to_srgb;
+    use crate::core::test_support::linear_settings;
+    use crate::models::BlendMode;
+
+    fn pixel_image(pixel: [f32; 4]) -> Rgba32FImage {
+        ImageBuffer::from_pixel(3, 2, Rgba(pixel))
+    }
+
+    fn tone_mapped(tone_mapping: ToneMapping, pixel: [f32; 4]) -> [f32; 4] {
+        let mut img = pixel_image(pixel);
+        tone_mapping.apply(&mut img);
+        img.get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn tone_mapping_operators_keep_alpha() {
+        let bright = [4.0, 2.0, 0.5, 0.5];
+        assert_eq!(tone_mapped(ToneMapping::Clip, bright), bright);
+        assert_eq!(tone_mapped(ToneMapping::Exposure { stops: -1.0 }, bright), [2.0, 1.0, 0.25, 0.5]);
+
+        // Reinhard maps the luminance to `l / (1 + l)` and keeps the ratio of the channels
+        let [r, g, b, a] = tone_mapped(ToneMapping::Reinhard { white_point: None }, [3.0, 3.0, 3.0, 0.5]);
+        assert!((r - 0.75).abs() < 1e-6 && r == g && g == b);
+        assert_eq!(a, 0.5);
+        let [r, g, _, _] = tone_mapped(ToneMapping::Reinhard { white_point: None }, bright);
+        assert!((r / g - 2.0).abs() < 1e-5);
+        // Luminance at the white point maps to white
+        let [r, ..] = tone_mapped(ToneMapping::Reinhard { white_point: Some(3.0) }, [3.0, 3.0, 3.0, 1.0]);
+        assert!((r - 1.0).abs() < 1e-6);
+
+        let [r, g, b, a] = tone_mapped(ToneMapping::Filmic, [0.0, 0.18, 100.0, 0.5]);
+        assert_eq!((r, a), (0.0, 0.5));
+        assert!(g > 0.0 && g < 0.5 && b > 0.99 && b <= 1.0);
+    }
+
+    #[test]
+    fn sixteen_bit_encoding_clips_and_encodes_srgb() {
+        let encoded = to_rgba16(pixel_image([0.5, 2.0, -1.0, 0.25]));
+        let expected_half = (linear_to_srgb(0.5) * 65535.0).round() as u16;
+        assert_eq!(encoded.get_pixel(2, 1).0, [expected_half, u16::MAX, 0, 16384]);
+    }
+
+    #[test]
+    fn high_depth_formats_are_written_with_their_depth() {
+        let output_dir = std::env::temp_dir().join(format!("output_test_{}", std::process::id()));
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let settings = linear_settings(BlendMode::WeightedMean);
+        let img = pixel_image([0.5, 1.5, 0.0, 1.0]);
+        let write = |format| {
+            let output_settings = OutputSettings {
+                format,
+                ..OutputSettings::default()
+            };
+            let path = save_long_exposure_image(img.clone(), &output_settings, &settings, &output_dir, "image").unwrap();
+            image::open(path).unwrap()
+        };
+
+        let expected_16bit = [(linear_to_srgb(0.5) * 65535.0).round() as u16, u16::MAX, 0, u16::MAX];
+        for format in [OutputFormat::Png16, OutputFormat::Tiff16] {
+            let DynamicImage::ImageRgba16(decoded) = write(format) else {
+                panic!("{:?} was not written with 16 bits per channel", format);
+            };
+            assert_eq!(decoded.get_pixel(1, 1).0, expected_16bit, "{:?}", format);
+        }
+        // EXR keeps linear light values above 1
+        let decoded = write(OutputFormat::Exr);
+        assert_eq!(decoded.color(), image::ColorType::Rgba32F);
+        assert_eq!(decoded.to_rgba32f().get_pixel(1, 1).0, [0.5, 1.5, 0.0, 1.0]);
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn browser_preview_is_written_next_to_tiff_and_exr() {
+        let output_dir = std::env::temp_dir().join(format!("preview_test_{}", std::process::id()));
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let settings = linear_settings(BlendMode::WeightedMean);
+        for format in [OutputFormat::Tiff16, OutputFormat::Exr, OutputFormat::Png16] {
+            let output_settings = OutputSettings {
+                format,
+                ..OutputSettings::default()
+            };
+            let img = pixel_image([0.5, 1.5, 0.0, 1.0]);
+            let base_name = format!("image_{}", format.extension());
+            let path = save_long_exposure_image(img, &output_settings, &settings, &output_dir, &base_name).unwrap();
+            let preview_path = path.with_extension("preview.png");
+            if format == OutputFormat::Png16 {
+                assert!(!preview_path.exists());
+                continue;
+            }
+            let preview = image::open(&preview_path).unwrap();
+            assert_eq!(preview.color(), image::ColorType::Rgba8, "{:?}", format);
+            assert_eq!(preview.to_rgba8().get_pixel(0, 0).0, [linear_to_srgb8(0.5), 255, 0, 255], "{:?}", format);
+        }
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn limit_dimensions_keeps_the_aspect_ratio() {
+        let img = Rgba32FImage::new(400, 100);
+        assert_eq!(limit_dimensions(img.clone(), 100).dimensions(), (100, 25));
+        assert_eq!(limit_dimensions(img.clone(), 400).dimensions(), (400, 100));
+        // 0 means no limit
+        assert_eq!(limit_dimensions(img, 0).dimensions(), (400, 100));
+        assert_eq!(limit_dimensions(Rgba32FImage::new(10, 1000), 20).dimensions(), (1, 20));
+    }
+
+    #[test]
+    fn resize_keeps_the_aspect_ratio_of_a_single_side() {
+        let img = Rgba32FImage::new(40, 20);
+        assert_eq!(resize_to(img.clone(), Some(10), None).dimensions(), (10, 5));
+        assert_eq!(resize_to(img.clone(), None, Some(30)).dimensions(), (60, 30));
+        assert_eq!(resize_to(img.clone(), Some(7), Some(9)).dimensions(), (7, 9));
+        assert_eq!(resize_to(img, None, None).dimensions(), (40, 20));
+        // The derived height of a narrow image is bounded as well
+        assert_eq!(
+            resized_dimensions((1, 64), Some(1024), None),
+            Some((MAX_OUTPUT_DIMENSION / 64, MAX_OUTPUT_DIMENSION))
+        );
+    }
+
+    #[test]
+    fn write_image_encodes_every_format() {
+        let output_dir = std::env::temp_dir().join(format!("write_image_test_{}", std::process::id()));
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(16, 8, |x, y| {
+            Rgba([(x * 16) as u8, (y * 32) as u8, 128, 255])
+        }));
+        let write = |format: OutputFormat| {
+            let path = output_dir.join(format!("image_{:?}.{}", format, format.extension()));
+            write_image(&img, format, &path).unwrap();
+            path
+        };
+
+        // JPEG has no alpha channel
+        let jpeg = image::open(write(OutputFormat::Jpeg { quality: 90 })).unwrap();
+        assert_eq!((jpeg.color(), jpeg.dimensions()), (image::ColorType::Rgb8, (16, 8)));
+        let lossless = image::open(write(OutputFormat::Webp {
+            lossless: true,
+            quality: 80,
+        }))
+        .unwrap();
+        assert_eq!(lossless.to_rgba8(), img.to_rgba8());
+        let lossy = image::open(write(OutputFormat::Webp {
+            lossless: false,
+            quality: 80,
+        }))
+        .unwrap();
+        assert_eq!(lossy.dimensions(), (16, 8));
+        // AVIF can not be decoded without dav1d, so only the container is checked
+        let avif = std::fs::read(write(OutputFormat::Avif { quality: 80, speed: 10 })).unwrap();
+        assert_eq!(&avif[4..12], b"ftypavif");
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use image::{ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::core::accumulator::BlendSettings;
+use crate::models::{BlendColorSpace, BlendMode, ClippingCenter, FrameData};
+
+/// Upper bound for the decoded samples held in memory while rendering a band.
+pub const SAMPLE_STACK_BUDGET_BYTES: usize = 512 * 1024 * 1024;
+
+/// Smallest spread sigma clipping assumes, one step of the 8-bit frames.
+const MIN_CLIPPING_SPREAD: f32 = 1.0 / 255.0;
+
+/// Combines the samples of one pixel with their weights into a color and the number of samples
+/// it rejected.
+pub type StackPixelFn = Box<dyn Fn(&mut [(Rgba<u8>, f32)]) -> ([f32; 4], usize) + Sync>;
+
+/// The pixel function of a blend mode that needs all samples of a pixel at once, and the color
+/// space its results are in.
+pub fn stack_pixel_fn(settings: &BlendSettings) -> (StackPixelFn, BlendColorSpace) {
+    match settings.blend_mode {
+        BlendMode::SigmaClipping {
+            kappa,
+            iterations,
+            center,
+        } => {
+            let decoding_table = settings.color_space.decoding_table();
+            let pixel_fn: StackPixelFn = Box::new(move |samples| {
+                sigma_clipped_pixel(samples, &decoding_table, kappa, iterations, center)
+            });
+            (pixel_fn, settings.color_space)
+        }
+        // The sRGB transfer function is monotonic, so the median is the same in every
+        // color space and is taken from the encoded values directly. It is also the
+        // background plate of stroboscopic renders
+        _ => (Box::new(|samples| (median_pixel(samples), 0)), BlendColorSpace::Srgb),
+    }
+}
+
+/**
+
+Renders blend modes that need every sample of a pixel at once (e.g. the median).
+Holding all frames in memory does not scale, so the image is processed in horizontal bands:
+for every band each frame is decoded, only the rows of the band are kept and the pixel
+function is evaluated once all frames were read. Frames are decoded once per band, which
+trades CPU time for a memory footprint that is bounded by `SAMPLE_STACK_BUDGET_BYTES`.
+# Arguments
+- `frames`: The frames to stack, the weights are passed through to `pixel_fn`.
+- `(width, height)`: The dimensions all frames share.
+- `load_frame`: Decodes a frame by its frame number.
+- `pixel_fn`: Combines the samples of one pixel into a color normalized to `[0,1]` and the number
+  of samples it rejected.
+# Returns
+- The stacked image and the total number of rejected samples, or the error of the first frame
+  that could not be loaded.
+
+ */
+pub fn render_in_bands<L, P>(
+    frames: &[FrameData],
+    (width, height): (u32, u32),
+    load_frame: L,
+    pixel_fn: P,
+) -> Result<(Rgba32FImage, usize), String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+    P: Fn(&mut [(Rgba<u8>, f32)]) -> ([f32; 4], usize) + Sync,
+{
+    let row_bytes = width as usize * 4;
+    let band_rows = (SAMPLE_STACK_BUDGET_BYTES / (frames.len().max(1) * row_bytes).max(1))
+        .clamp(1, height.max(1) as usize);
+    let mut long_exposure_img: Rgba32FImage = ImageBuffer::new(width, height);
+    let mut rejected_samples = 0;
+
+    for (band_index, out_band) in long_exposure_img
+        .chunks_mut(band_rows * row_bytes)
+        .enumerate()
+    {
+        let first_row = band_index * band_rows;
+        let band_bytes = out_band.len();
+        let frame_numbers: Vec<usize> = frames.iter().map(|frame| frame.frame_number).collect();
+        let samples = decode_band(&frame_numbers, (width, height), first_row, band_bytes, &load_frame)?;
+
+        let slots: Vec<(usize, f32)> = frames
+            .iter()
+            .enumerate()
+            .map(|(slot, frame)| (slot, frame.frame_weight))
+            .collect();
+        rejected_samples += stack_band(&samples, band_bytes, &slots, out_band, &pixel_fn);
+    }
+
+    Ok((long_exposure_img, rejected_samples))
+}
+
+/**
+
+Decodes frames and keeps only the rows of one band of each of them.
+# Arguments
+- `frame_numbers`: The frames to decode.
+- `(width, height)`: The dimensions all frames share.
+- `first_row`: The first row of the band.
+- `band_bytes`: The size of the band of one frame.
+- `load_frame`: Decodes a frame by its frame number.
+# Returns
+- The bands of all frames one after another, or the error of the first frame that could not be loaded.
+
+ */
+pub fn decode_band<L>(
+    frame_numbers: &[usize],
+    (width, height): (u32, u32),
+    first_row: usize,
+    band_bytes: usize,
+    load_frame: L,
+) -> Result<Vec<u8>, String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+{
+    let mut samples = vec![0u8; band_bytes * frame_numbers.len()];
+    samples
+        .par_chunks_mut(band_bytes.max(1))
+        .zip(frame_numbers.par_iter())
+        .try_for_each(|(frame_band, frame_number)| {
+            let img = load_frame(*frame_number)?;
+            if img.dimensions() != (width, height) {
+                return Err(format!(
+                    "Frame {} has dimensions {:?}, expected {:?}",
+                    frame_number,
+                    img.dimensions(),
+                    (width, height)
+                ));
+            }
+            let start = first_row * width as usize * 4;
+            frame_band.copy_from_slice(&img.as_raw()[start..start + band_bytes]);
+            Ok(())
+        })?;
+    Ok(samples)
+}
+
+/**
+
+Evaluates the pixel function for every pixel of a band.
+# Arguments
+- `samples`: The rows of the band of several frames, `band_bytes` per frame.
+- `band_bytes`: The size of the band of one frame.
+- `slots`: The frames to stack, as index into `samples` and weight.
+- `out_band`: The rows of the band in the output image.
+- `pixel_fn`: Combines the samples of one pixel, see `render_in_bands`.
+# Returns
+- The number of rejected samples.
+
+ */
+pub fn stack_band<P>(
+    samples: &[u8],
+    band_bytes: usize,
+    slots: &[(usize, f32)],
+    out_band: &mut [f32],
+    pixel_fn: P,
+) -> usize
+where
+    P: Fn(&mut [(Rgba<u8>, f32)]) -> ([f32; 4], usize) + Sync,
+{
+    out_band
+        .par_chunks_mut(4)
+        .enumerate()
+        .map_init(
+            || Vec::with_capacity(slots.len()),
+            |pixel_samples, (pixel_index, out)| {
+                let offset = pixel_index * 4;
+                pixel_samples.clear();
+                pixel_samples.extend(slots.iter().map(|(slot, weight)| {
+                    let start = slot * band_bytes + offset;
+                    let data = &samples[start..start + 4];
+                    (Rgba([data[0], data[1], data[2], data[3]]), *weight)
+                }));
+                let (color, rejected) = pixel_fn(pixel_samples);
+                out.copy_from_slice(&color);
+                rejected
+            },
+        )
+        .sum()
+}
+
+/// Weighted median per channel, frames with a higher weight count as more samples.
+pub fn median_pixel(samples: &mut [(Rgba<u8>, f32)]) -> [f32; 4] {
+    let total_weight: f32 = samples
+        .iter()
+        .map(|(Rgba(data), weight)| weight.max(0.0) * data[3] as f32 / 255.0)
+        .sum();
+    if total_weight <= 0.0 {
+        return [0.0; 4];
+    }
+
+    let mut result = [0.0; 4];
+    for (channel, value) in result.iter_mut().enumerate() {
+        samples.sort_unstable_by_key(|(Rgba(data), _)| data[channel]);
+        let mut cumulative_weight = 0.0;
+        for (Rgba(data), weight) in samples.iter() {
+            cumulative_weight += weight.max(0.0) * data[3] as f32 / 255.0;
+            if cumulative_weight >= total_weight / 2.0 {
+                *value = data[channel] as f32 / 255.0;
+                break;
+            }
+        }
+    }
+
+    result
+}
+
+/**
+
+Weighted mean per channel after iteratively rejecting outliers (sigma / kappa clipping).
+In every iteration the center and spread of the remaining samples are computed and samples
+further than `kappa` times the spread from the center are dropped. As the samples are sorted
+per channel, the remaining samples always form a contiguous range.
+# Arguments
+- `samples`: The samples of the pixel with their frame weights.
+- `decoding_table`: Maps 8-bit values to the blend color space the statistics are computed in.
+- `kappa`: Rejection threshold in multiples of the spread.
+- `iterations`: Maximum number of rejection passes, it stops early once nothing changes.
+- `center`: Whether mean and standard deviation or median and MAD are used.
+# Returns
+- The clipped mean in the blend color space and the number of rejected channel values.
+
+ */
+pub fn sigma_clipped_pixel(
+    samples: &mut [(Rgba<u8>, f32)],
+    decoding_table: &[f32; 256],
+    kappa: f32,
+    iterations: u32,
+    center: ClippingCenter,
+) -> ([f32; 4], usize) {
+    let sample_weight = |(Rgba(data), weight): &(Rgba<u8>, f32)| weight.max(0.0) * data[3] as f32 / 255.0;
+    let mut result = [0.0; 4];
+    let mut rejected = 0;
+
+    // Alpha is not clipped, only the color channels are
+    let total_weight: f32 = samples.iter().map(|(_, weight)| weight.max(0.0)).sum();
+    if total_weight > 0.0 {
+        result[3] = samples
+            .iter()
+            .map(|(Rgba(data), weight)| weight.max(0.0) * data[3] as f32 / 255.0)
+            .sum::<f32>()
+            / total_weight;
+    }
+
+    for (channel, value) in result[..3].iter_mut().enumerate() {
+        samples.sort_unstable_by_key(|(Rgba(data), _)| data[channel]);
+        let value_at = |i: usize| decoding_table[samples[i].0 .0[channel] as usize];
+        let weight_at = |i: usize| sample_weight(&samples[i]);
+
+        let (mut low, mut high) = (0, samples.len());
+        for _ in 0..iterations {
+            let range_weight: f32 = (low..high).map(weight_at).sum();
+            if range_weight <= 0.0 {
+                break;
+            }
+            let (center_value, spread) = match center {
+                ClippingCenter::Mean => {
+                    let mean = (low..high).map(|i| value_at(i) * weight_at(i)).sum::<f32>() / range_weight;
+                    let variance = (low..high)
+                        .map(|i| weight_at(i) * (value_at(i) - mean).powi(2))
+                        .sum::<f32>()
+                        / range_weight;
+                    (mean, variance.sqrt())
+                }
+                ClippingCenter::Median => {
+                    let median_index = weighted_median_index(low, high, range_weight, weight_at);
+                    let median = value_at(median_index);
+                    let mad = weighted_mad(low, high, median_index, range_weight, value_at, weight_at);
+                    // Scales the MAD to the standard deviation of normally distributed samples
+                    (median, 1.4826 * mad)
+                }
+            };
+            // Without a floor every sample that differs from the center is an outlier when most
+            // frames agree exactly on a static part of the scene, so the spread is at least one
+            // 8-bit step
+            let limit = kappa * spread.max(MIN_CLIPPING_SPREAD);
+            let new_low = (low..high)
+                .find(|i| value_at(*i) >= center_value - limit)
+                .unwrap_or(high);
+            let new_high = (new_low..high)
+                .rev()
+                .find(|i| value_at(*i) <= center_value + limit)
+                .map_or(new_low, |i| i + 1);
+            if (new_low, new_high) == (low, high) {
+                break;
+            }
+            (low, high) = (new_low, new_high);
+        }
+
+        rejected += samples.len() - (high - low);
+        let range_weight: f32 = (low..high).map(weight_at).sum();
+        if range_weight > 0.0 {
+            *value = (low..high).map(|i| value_at(i) * weight_at(i)).sum::<f32>() / range_weight;
+        }
+    }
+
+    (result, rejected)
+}
+
+/// Index of the weighted median within the sorted range `low..high`.
+fn weighted_median_index(low: usize, high: usize, range_weight: f32, weight_at: impl Fn(usize) -> f32) -> usize {
+    let mut cumulative_weight = 0.0;
+    for i in low..high {
+        cumulative_weight += weight_at(i);
+        if cumulative_weight >= range_weight / 2.0 {
+            return i;
+        }
+    }
+    high - 1
+}
+
+/// Weighted median absolute deviation from the sample at `median_index`. The deviations grow
+/// outwards from the median in the sorted range, so they are visited in order by walking both
+/// directions at once.
+fn weighted_mad(
+    low: usize,
+    high: usize,
+    median_index: usize,
+    range_weight: f32,
+    value_at: impl Fn(usize) -> f32,
+    weight_at: impl Fn(usize) -> f32,
+) -> f32 {
+    let median = value_at(median_index);
+    let (mut below, mut above) = (median_index as isize - 1, median_index + 1);
+    let mut cumulative_weight = weight_at(median_index);
+    let mut deviation = 0.0;
+    while cumulative_weight < range_weight / 2.0 {
+        let below_deviation = (below >= low as isize).then(|| median - value_at(below as usize));
+        let above_deviation = (above < high).then(|| value_at(above) - median);
+        match (below_deviation, above_deviation) {
+            (Some(below_value), Some(above_value)) if below_value <= above_value => {
+                deviation = below_value;
+                cumulative_weight += weight_at(below as usize);
+                below -= 1;
+            }
+            (Some(below_value), None) => {
+                deviation = below_value;
+                cumulative_weight += weight_at(below as usize);
+                below -= 1;
+            }
+            (_, Some(above_value)) => {
+                deviation = above_value;
+                cumulative_weight += weight_at(above);
+                above += 1;
+            }
+            (None, None) => break,
+        }
+    }
+    deviation
+}
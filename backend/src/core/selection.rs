@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use crate::error::RenderError;
+use crate::models::{FrameData, FrameRange, KeyframeInterpolation, WeightKeyframe};
+
+/**
+
+Expands frame ranges and weight keyframes into the list of frames to blend.
+Every frame of the ranges gets the weight interpolated from the keyframes, explicitly listed
+frames override the weight of a frame or add frames outside the ranges.
+# Arguments
+- `frame_ranges`: Ranges of frame numbers with a step.
+- `weight_keyframes`: Weights at given frame numbers, without keyframes every frame weighs 1.
+- `explicit_frames`: Frames listed one by one, they take precedence over the ranges.
+- `last_frame`: Number of the last frame of the project, ranges must not go beyond it.
+# Returns
+- The frames ordered by frame number, or the explicit frames as they are if there are no ranges.
+  Invalid ranges are an `InvalidRequest`.
+
+ */
+pub fn expand_frame_selection(
+    frame_ranges: &[FrameRange],
+    weight_keyframes: &[WeightKeyframe],
+    explicit_frames: &[FrameData],
+    last_frame: usize,
+) -> Result<Vec<FrameData>, RenderError> {
+    if frame_ranges.is_empty() {
+        return Ok(explicit_frames.to_vec());
+    }
+
+    let mut keyframes = weight_keyframes.to_vec();
+    keyframes.sort_by_key(|keyframe| keyframe.frame);
+
+    let mut frames: BTreeMap<usize, FrameData> = BTreeMap::new();
+    for range in frame_ranges {
+        if range.step == 0 {
+            return Err(RenderError::InvalidRequest(
+                "The step of a frame range must be at least 1".to_string(),
+            ));
+        }
+        if range.start > range.end {
+            return Err(RenderError::InvalidRequest(format!(
+                "Frame range {}..{} starts after it ends",
+                range.start, range.end
+            )));
+        }
+        // Bounds the selection, so a huge range cannot exhaust the memory
+        if range.end > last_frame {
+            return Err(RenderError::InvalidRequest(format!(
+                "Frame range {}..{} ends after the last frame {} of the project",
+                range.start, range.end, last_frame
+            )));
+        }
+        for frame_number in (range.start..=range.end).step_by(range.step) {
+            frames.insert(
+                frame_number,
+                FrameData {
+                    frame_number,
+                    frame_weight: interpolated_weight(&keyframes, frame_number),
+                    freeze: false,
+                },
+            );
+        }
+    }
+    for frame in explicit_frames {
+        frames.insert(frame.frame_number, frame.clone());
+    }
+
+    Ok(frames.into_values().collect())
+}
+
+/// Weight at a frame number, keyframes must be sorted by frame. Before the first and after
+/// the last keyframe their weight is held.
+fn interpolated_weight(keyframes: &[WeightKeyframe], frame_number: usize) -> f32 {
+    let next_index = keyframes.partition_point(|keyframe| keyframe.frame <= frame_number);
+    match (next_index.checked_sub(1).map(|i| &keyframes[i]), keyframes.get(next_index)) {
+        (None, None) => 1.0,
+        (None, Some(next)) => next.weight,
+        (Some(previous), None) => previous.weight,
+        (Some(previous), Some(next)) => {
+            let t = (frame_number - previous.frame) as f32 / (next.frame - previous.frame) as f32;
+            let t = match previous.interpolation {
+                KeyframeInterpolation::Linear => t,
+                KeyframeInterpolation::EaseInOut => t * t * (3.0 - 2.0 * t),
+                KeyframeInterpolation::Hold => 0.0,
+            };
+            previous.weight + (next.weight - previous.weight) * t
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(frame: usize, weight: f32, interpolation: KeyframeInterpolation) -> WeightKeyframe {
+        WeightKeyframe {
+            frame,
+            weight,
+            interpolation,
+        }
+    }
+
+    #[test]
+    fn expands_ranges_with_keyframes_and_overrides() {
+        let ranges = [FrameRange {
+            start: 10,
+            end: 20,
+            step: 2,
+        }];
+        let keyframes = [
+            keyframe(20, 0.0, KeyframeInterpolation::Linear),
+            keyframe(10, 1.0, KeyframeInterpolation::Linear),
+        ];
+        let overrides = [FrameData {
+            frame_number: 14,
+            frame_weight: 5.0,
+            freeze: true,
+        }];
+
+        let frames = expand_frame_selection(&ranges, &keyframes, &overrides, 100).unwrap();
+        let expected = [(10, 1.0), (12, 0.8), (14, 5.0), (16, 0.4), (18, 0.2), (20, 0.0)];
+        assert_eq!(frames.len(), expected.len());
+        for (frame, (frame_number, weight)) in frames.iter().zip(expected) {
+            assert_eq!(frame.frame_number, frame_number);
+            assert!((frame.frame_weight - weight).abs() < 1e-6, "{:?}", frame);
+        }
+        assert!(frames[2].freeze);
+    }
+
+    #[test]
+    fn ranges_beyond_the_last_frame_are_rejected() {
+        let ranges = [FrameRange {
+            start: 1,
+            end: usize::MAX,
+            step: 1,
+        }];
+        assert!(matches!(
+            expand_frame_selection(&ranges, &[], &[], 100),
+            Err(RenderError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn interpolation_modes_between_keyframes() {
+        let weight_at = |interpolation| {
+            let keyframes = [keyframe(0, 0.0, interpolation), keyframe(4, 1.0, interpolation)];
+            interpolated_weight(&keyframes, 1)
+        };
+        assert_eq!(weight_at(KeyframeInterpolation::Linear), 0.25);
+        assert_eq!(weight_at(KeyframeInterpolation::EaseInOut), 0.15625);
+        assert_eq!(weight_at(KeyframeInterpolation::Hold), 0.0);
+    }
+}
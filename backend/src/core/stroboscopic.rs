@@ -0,0 +1,93 @@
+use image::{Rgba32FImage, RgbaImage};
+
+use crate::core::compositing::{composite_with_key_frame, difference_mask, feather_mask};
+use crate::core::long_exposure_image_logic::stream_frames;
+use crate::models::{BlendMode, FrameData};
+
+/**
+
+Composites the subjects of a stroboscopic render onto its background plate, other blend modes are
+left unchanged. Every `every`th frame starting with the first one is compared to the plate, the
+pixels that differ are its subject. Later frames are composited on top of earlier ones.
+# Arguments
+- `plate`: The linear light background plate, the subjects are composited onto it in place.
+- `blend_mode`: The blend mode of the render.
+- `frames_data`: The contributing frames in order, see `contributing_frames`.
+- `load_frame`: Decodes a frame by its frame number.
+# Returns
+- An error message if a frame could not be loaded.
+
+ */
+pub fn composite_stroboscopic<L>(
+    plate: &mut Rgba32FImage,
+    blend_mode: BlendMode,
+    frames_data: &[FrameData],
+    load_frame: L,
+) -> Result<(), String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+{
+    let BlendMode::Stroboscopic {
+        every,
+        opacity,
+        threshold,
+        feather,
+    } = blend_mode
+    else {
+        return Ok(());
+    };
+    let max_weight = frames_data
+        .iter()
+        .map(|frame| frame.frame_weight)
+        .fold(0.0f32, f32::max);
+    let sequence: Vec<&FrameData> = frames_data.iter().step_by(every.max(1)).collect();
+    let frame_numbers: Vec<usize> = sequence.iter().map(|frame| frame.frame_number).collect();
+
+    // Subjects are always separated from the clean plate, not from the earlier subjects
+    let background = plate.clone();
+    stream_frames(&frame_numbers, plate.dimensions(), load_frame, |index, img| {
+        let frame_opacity = if max_weight > 0.0 {
+            (opacity * sequence[index].frame_weight / max_weight).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let mut mask = feather_mask(difference_mask(&background, img, threshold), feather);
+        for share in mask.iter_mut() {
+            *share = 1.0 - frame_opacity * (1.0 - *share);
+        }
+        composite_with_key_frame(plate, img, &mask);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+    use crate::core::long_exposure_image_logic::render_frames;
+    use crate::core::output::to_rgba8;
+    use crate::core::test_support::{frames_data, linear_settings, load_from};
+
+    #[test]
+    fn every_nth_subject_is_shown_on_the_plate() {
+        const BACKGROUND: [u8; 4] = [40, 60, 80, 255];
+        const SUBJECT: [u8; 4] = [250, 250, 250, 255];
+        // A bright subject moves one pixel per frame over a static background
+        let frames: Vec<RgbaImage> = (0..6)
+            .map(|frame_number| {
+                ImageBuffer::from_fn(6, 1, |x, _| Rgba(if x == frame_number { SUBJECT } else { BACKGROUND }))
+            })
+            .collect();
+        let frames_data = frames_data(vec![1.0; frames.len()]);
+        let settings = linear_settings(BlendMode::Stroboscopic {
+            every: 2,
+            opacity: 1.0,
+            threshold: 0.12,
+            feather: 0.0,
+        });
+
+        let (long_exposure_img, _) = render_frames(&frames_data, &settings, (6, 1), load_from(&frames)).unwrap();
+        let pixels: Vec<[u8; 4]> = to_rgba8(long_exposure_img).pixels().map(|pixel| pixel.0).collect();
+        assert_eq!(pixels, vec![SUBJECT, BACKGROUND, SUBJECT, BACKGROUND, SUBJECT, BACKGROUND]);
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures shared by the tests of the renderers.
+
+use image::RgbaImage;
+
+use crate::core::accumulator::BlendSettings;
+use crate::models::{BlendColorSpace, BlendMode, FrameData, WeightingSettings};
+
+/// Selects one frame per weight, numbered by its position and none of them frozen.
+pub fn frames_data(weights: impl IntoIterator<Item = f32>) -> Vec<FrameData> {
+    weights
+        .into_iter()
+        .enumerate()
+        .map(|(frame_number, frame_weight)| FrameData {
+            frame_number,
+            frame_weight,
+            freeze: false,
+        })
+        .collect()
+}
+
+/// Loads a frame by using its number as index into already decoded frames.
+pub fn load_from(frames: &[RgbaImage]) -> impl Fn(usize) -> Result<RgbaImage, String> + Copy + Sync + '_ {
+    move |frame_number| Ok(frames[frame_number].clone())
+}
+
+/// Blends in linear light with the default weighting.
+pub fn linear_settings(blend_mode: BlendMode) -> BlendSettings {
+    BlendSettings {
+        blend_mode,
+        weighting: WeightingSettings::default(),
+        color_space: BlendColorSpace::Linear,
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::Range;
+use std::path::Path;
+
+use image::{imageops, ImageBuffer, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::core::accumulator::{Accumulator, BlendSettings};
+use crate::core::long_exposure_image_logic::{accumulate_frames, contributing_frames, render_frames};
+use crate::core::time_slice::render_time_slice;
+use crate::models::{BlendMode, FrameData, RejectionStatistics};
+
+/// Most tiles rendered at the same time, each holds its rows of the frames it decodes.
+pub const MAX_CONCURRENT_TILES: usize = 4;
+
+/**
+
+Renders the image in horizontal tiles, up to `MAX_CONCURRENT_TILES` of them in parallel.
+Each tile only keeps its rows of every frame, so the memory needed for accumulation buffers
+and decoded frames is bounded by the tile size instead of the image size. PNG rows can only be
+decoded in order, so every tile decodes each frame from the top down to its last row and the
+decoding time grows with the number of tiles. Every tile runs the
+same per-pixel computation as an untiled render of the same request, so the result is
+bit-identical to it.
+Tiles span the full image width, as PNG frames are decoded row by row anyway.
+Stroboscopic renders feather their subjects across tile borders and cannot be tiled.
+# Arguments
+- `frames_data`: The frames to include and their weights.
+- `settings`: The blend mode, weighting and color space of the render.
+- `dimensions`: The dimensions all frames share.
+- `tile_rows`: The number of rows per tile, at least 1.
+- `load_rows`: Decodes the given rows of a frame by its frame number, without decoding the
+  whole frame.
+# Returns
+- The blended linear light image and, for modes that reject samples, how many were rejected,
+  or an error message.
+
+ */
+pub fn render_tiled<L>(
+    frames_data: &[FrameData],
+    settings: &BlendSettings,
+    (width, height): (u32, u32),
+    tile_rows: u32,
+    load_rows: L,
+) -> Result<(Rgba32FImage, Option<RejectionStatistics>), String>
+where
+    L: Fn(usize, Range<u32>) -> Result<RgbaImage, String> + Sync,
+{
+    if let BlendMode::Stroboscopic { .. } = settings.blend_mode {
+        return Err("Stroboscopic renders cannot be tiled".to_string());
+    }
+    let tile_rows = tile_rows.clamp(1, height.max(1));
+    let tiles: Vec<Range<u32>> = (0..height)
+        .step_by(tile_rows as usize)
+        .map(|first_row| first_row..(first_row + tile_rows).min(height))
+        .collect();
+
+    let render_tile = |rows: &Range<u32>| {
+        let load_tile = |frame_number| load_rows(frame_number, rows.clone());
+        if let BlendMode::TimeSlice { layout, blend } = settings.blend_mode {
+            // Slices are laid out over the whole image, not the tile
+            let frames_data = contributing_frames(frames_data)?;
+            let tile = render_time_slice(
+                &frames_data,
+                layout,
+                blend,
+                settings.color_space,
+                (width, height),
+                rows.clone(),
+                load_tile,
+            )?;
+            Ok((tile, None))
+        } else if settings.blend_mode.is_reversible() {
+            let frames_data = contributing_frames(frames_data)?;
+            let accumulator: Accumulator<f64> = accumulate_frames(&frames_data, settings, load_tile)?;
+            Ok((accumulator.finish(), None))
+        } else {
+            render_frames(frames_data, settings, (width, rows.len() as u32), load_tile)
+        }
+    };
+
+    let mut long_exposure_img: Rgba32FImage = ImageBuffer::new(width, height);
+    let mut rejection: Option<RejectionStatistics> = None;
+    // Only a bounded number of tiles holds its rows of the frames at the same time
+    for tile_batch in tiles.chunks(MAX_CONCURRENT_TILES) {
+        let rendered_tiles = tile_batch
+            .par_iter()
+            .map(render_tile)
+            .collect::<Result<Vec<(Rgba32FImage, Option<RejectionStatistics>)>, String>>()?;
+        for (rows, (tile, tile_rejection)) in tile_batch.iter().zip(rendered_tiles) {
+            let start = rows.start as usize * width as usize * 4;
+            long_exposure_img.as_mut()[start..start + tile.len()].copy_from_slice(&tile);
+            if let Some(tile_rejection) = tile_rejection {
+                let total = rejection.get_or_insert(RejectionStatistics {
+                    rejected_samples: 0,
+                    total_samples: 0,
+                });
+                total.rejected_samples += tile_rejection.rejected_samples;
+                total.total_samples += tile_rejection.total_samples;
+            }
+        }
+    }
+
+    Ok((long_exposure_img, rejection))
+}
+
+/**
+
+Decodes only the given region of a PNG frame. Rows are decoded in order and decoding stops after
+the last requested row, rows before the region are decoded but not kept.
+Returns `None` for PNGs that cannot be read row by row with the same result as decoding the
+whole image (see `png_readable_by_rows`), those have to be decoded completely.
+# Arguments
+- `frame_path`: The PNG file of the frame.
+- `columns`: The columns to keep.
+- `rows`: The rows to keep.
+# Returns
+- The region as RGBA image, `None` if the PNG has to be decoded completely, or an error message.
+
+ */
+pub fn read_png_region(
+    frame_path: &Path,
+    columns: Range<u32>,
+    rows: Range<u32>,
+) -> Result<Option<RgbaImage>, String> {
+    let Some(mut reader) = open_png_rows(frame_path)? else {
+        return Ok(None);
+    };
+    let (color_type, _) = reader.output_color_type();
+    if columns.end > reader.info().width {
+        return Err(format!("Image has less than {} columns", columns.end));
+    }
+
+    let samples = color_type.samples();
+    let row_bytes = columns.start as usize * samples..columns.end as usize * samples;
+    let mut region: RgbaImage = ImageBuffer::new(columns.len() as u32, rows.len() as u32);
+    let mut region_rows = region.chunks_exact_mut(columns.len() * 4);
+    for row_index in 0..rows.end {
+        let row = reader
+            .next_row()
+            .map_err(|e| format!("Failed to decode image: {}", e))?
+            .ok_or_else(|| format!("Image has less than {} rows", rows.end))?;
+        if row_index < rows.start {
+            continue;
+        }
+        let Some(out) = region_rows.next() else {
+            break;
+        };
+        expand_to_rgba(&row.data()[row_bytes.clone()], color_type, out);
+    }
+
+    Ok(Some(region))
+}
+
+/// Whether the rows of a PNG frame can be decoded one by one with the same result as decoding the
+/// whole image, which is not the case for interlaced or 16-bit PNGs. Only reads the header.
+pub fn png_readable_by_rows(frame_path: &Path) -> Result<bool, String> {
+    Ok(open_png_rows(frame_path)?.is_some())
+}
+
+/// Opens a PNG for decoding row by row, `None` if its rows cannot be read one by one.
+fn open_png_rows(frame_path: &Path) -> Result<Option<png::Reader<BufReader<File>>>, String> {
+    let file = File::open(frame_path).map_err(|e| format!("Failed to open image: {}", e))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    // Same transformations as the PNG decoder of `image`
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let reader = decoder
+        .read_info()
+        .map_err(|e| format!("Failed to open image: {}", e))?;
+    let (_, bit_depth) = reader.output_color_type();
+    if reader.info().interlaced || bit_depth != png::BitDepth::Eight {
+        return Ok(None);
+    }
+    Ok(Some(reader))
+}
+
+fn expand_to_rgba(row: &[u8], color_type: png::ColorType, out: &mut [u8]) {
+    match color_type {
+        png::ColorType::Rgba => out.copy_from_slice(row),
+        png::ColorType::Rgb => {
+            for (pixel, rgb) in out.chunks_exact_mut(4).zip(row.chunks_exact(3)) {
+                pixel.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+            }
+        }
+        png::ColorType::GrayscaleAlpha => {
+            for (pixel, gray_alpha) in out.chunks_exact_mut(4).zip(row.chunks_exact(2)) {
+                pixel.copy_from_slice(&[gray_alpha[0], gray_alpha[0], gray_alpha[0], gray_alpha[1]]);
+            }
+        }
+        // Palettes are expanded to RGB(A) by the decoder, which leaves grayscale
+        _ => {
+            for (pixel, gray) in out.chunks_exact_mut(4).zip(row) {
+                pixel.copy_from_slice(&[*gray, *gray, *gray, 255]);
+            }
+        }
+    }
+}
+
+/// Cuts the given region out of a decoded frame.
+pub fn crop_region_of(img: &RgbaImage, columns: Range<u32>, rows: Range<u32>) -> RgbaImage {
+    imageops::crop_imm(img, columns.start, rows.start, columns.len() as u32, rows.len() as u32).to_image()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::core::test_support::{frames_data, linear_settings};
+    use crate::models::{RelativePoint, SliceLayout};
+
+    fn synthetic_frame(frame_number: usize) -> RgbaImage {
+        ImageBuffer::from_fn(5, 7, |x, y| {
+            let value = ((frame_number * 31 + x as usize * 17 + y as usize * 43) % 256) as u8;
+            Rgba([value, 255 - value, value / 3, 255])
+        })
+    }
+
+    #[test]
+    fn tiled_render_is_bit_identical() {
+        let frames_data = frames_data((0..4).map(|frame_number| 1.0 + frame_number as f32));
+        let load = |frame_number| Ok(synthetic_frame(frame_number));
+        let load_rows = |frame_number, rows| Ok(crop_region_of(&synthetic_frame(frame_number), 0..5, rows));
+
+        let time_slice = BlendMode::TimeSlice {
+            layout: SliceLayout::Radial {
+                center: RelativePoint { x: 0.3, y: 0.6 },
+            },
+            blend: 0.5,
+        };
+        for blend_mode in [BlendMode::WeightedMean, BlendMode::Lighten, BlendMode::Median, time_slice] {
+            let settings = linear_settings(blend_mode);
+            let untiled = if blend_mode.is_reversible() {
+                let accumulator: Accumulator<f64> =
+                    accumulate_frames(&frames_data, &settings, load).unwrap();
+                accumulator.finish()
+            } else {
+                render_frames(&frames_data, &settings, (5, 7), load).unwrap().0
+            };
+            // More tiles than run at the same time, and tiles of a few rows
+            for tile_rows in [1, 3] {
+                let (tiled, _) = render_tiled(&frames_data, &settings, (5, 7), tile_rows, load_rows).unwrap();
+                assert_eq!(tiled.as_raw(), untiled.as_raw(), "{:?} in tiles of {}", blend_mode, tile_rows);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_only_requested_png_region() {
+        let frame = synthetic_frame(1);
+        let frame_path = std::env::temp_dir().join(format!("tiling_test_{}.png", std::process::id()));
+        image::DynamicImage::ImageRgba8(frame.clone()).to_rgb8().save(&frame_path).unwrap();
+
+        let region = read_png_region(&frame_path, 1..4, 2..5).unwrap().unwrap();
+        std::fs::remove_file(&frame_path).unwrap();
+        assert_eq!(region, crop_region_of(&frame, 1..4, 2..5));
+    }
+
+    #[test]
+    fn sixteen_bit_pngs_are_not_read_by_rows() {
+        let frame_path = std::env::temp_dir().join(format!("tiling_16bit_test_{}.png", std::process::id()));
+        image::DynamicImage::ImageRgba8(synthetic_frame(1)).to_rgba16().save(&frame_path).unwrap();
+
+        let readable = png_readable_by_rows(&frame_path).unwrap();
+        let region = read_png_region(&frame_path, 0..5, 0..3).unwrap();
+        std::fs::remove_file(&frame_path).unwrap();
+        assert!(!readable);
+        assert!(region.is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use std::ops::Range;
+
+use image::{ImageBuffer, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+use crate::core::long_exposure_image_logic::stream_frames;
+use crate::models::{BlendColorSpace, FrameData, SliceLayout};
+
+/// The slice a pixel falls into and the share it takes from the neighbouring slice on the nearer
+/// edge, negative for the previous and positive for the next slice.
+#[derive(Clone, Copy, Default)]
+struct PixelSlice {
+    slice: u32,
+    neighbour_share: f32,
+}
+
+impl PixelSlice {
+    /// The share of `slice` in this pixel, 0 if it neither falls into it nor fades into it.
+    fn share_of(&self, slice: usize) -> f32 {
+        let own = self.slice as usize;
+        if own == slice {
+            1.0 - self.neighbour_share.abs()
+        } else if (self.neighbour_share < 0.0 && own == slice + 1) || (self.neighbour_share > 0.0 && own + 1 == slice) {
+            self.neighbour_share.abs()
+        } else {
+            0.0
+        }
+    }
+}
+
+impl SliceLayout {
+    /// Position of a point along the slices, 0 at the start of the first and 1 at the end of the
+    /// last slice. `x` and `y` are relative to the image like a `RelativePoint`, `aspect` is its
+    /// width divided by its height, so rings are circles in the image.
+    fn position(&self, x: f32, y: f32, aspect: f32) -> f32 {
+        let position = match self {
+            SliceLayout::Vertical => x,
+            SliceLayout::Horizontal => y,
+            SliceLayout::Radial { center } => {
+                let distance = |x: f32, y: f32| ((x - center.x) * aspect).hypot(y - center.y);
+                let farthest_corner = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+                    .into_iter()
+                    .map(|(corner_x, corner_y)| distance(corner_x, corner_y))
+                    .fold(0.0, f32::max);
+                distance(x, y) / farthest_corner.max(f32::EPSILON)
+            }
+            SliceLayout::Gradient { start, end } => {
+                let (dx, dy) = ((end.x - start.x) * aspect, end.y - start.y);
+                let length_squared = (dx * dx + dy * dy).max(f32::EPSILON);
+                ((x - start.x) * aspect * dx + (y - start.y) * dy) / length_squared
+            }
+        };
+        position.clamp(0.0, 1.0)
+    }
+}
+
+/// A time slice of some rows of an image, the frames of its slices are added one at a time and in
+/// any order. Each pixel keeps its slice and how much of it fades into the neighbouring slice.
+pub struct TimeSlice {
+    pixel_slices: Vec<PixelSlice>,
+    /// Pixels of each row ordered by their slice, so a frame only visits the pixels it shows up in.
+    slice_order: Vec<u32>,
+    slice_count: usize,
+    image: Rgba32FImage,
+    decoding_table: [f32; 256],
+    color_space: BlendColorSpace,
+}
+
+impl TimeSlice {
+    /// Lays the slices out over an image of `(width, height)` and keeps the pixels of `rows`, see
+    /// `render_time_slice` for the arguments.
+    pub fn new(
+        frames_data: &[FrameData],
+        layout: SliceLayout,
+        blend: f32,
+        color_space: BlendColorSpace,
+        (width, height): (u32, u32),
+        rows: Range<u32>,
+    ) -> Self {
+        let total_weight: f32 = frames_data.iter().map(|frame| frame.frame_weight).sum();
+        let mut slice_starts: Vec<f32> = frames_data
+            .iter()
+            .scan(0.0, |start, frame| {
+                let slice_start = *start / total_weight;
+                *start += frame.frame_weight;
+                Some(slice_start)
+            })
+            .collect();
+        slice_starts.push(1.0);
+        let fade_width = blend.clamp(0.0, 1.0) / 2.0;
+        let aspect = width as f32 / height.max(1) as f32;
+        let row_len = width.max(1) as usize;
+
+        let mut pixel_slices = vec![PixelSlice::default(); width as usize * rows.len()];
+        pixel_slices
+            .par_chunks_mut(row_len)
+            .zip(rows.clone())
+            .for_each(|(row_slices, y)| {
+                for (x, pixel_slice) in row_slices.iter_mut().enumerate() {
+                    let position = layout.position(
+                        (x as f32 + 0.5) / width as f32,
+                        (y as f32 + 0.5) / height as f32,
+                        aspect,
+                    );
+                    let slice = slice_starts[..frames_data.len()]
+                        .partition_point(|start| *start <= position)
+                        .clamp(1, frames_data.len())
+                        - 1;
+                    let slice_width = (slice_starts[slice + 1] - slice_starts[slice]).max(f32::EPSILON);
+                    let within_slice = (position - slice_starts[slice]) / slice_width;
+                    // The neighbour on the nearer edge gets up to half of the pixel right at the edge
+                    let (has_neighbour, edge_distance, direction) = if within_slice < 0.5 {
+                        (slice > 0, within_slice, -1.0)
+                    } else {
+                        (slice + 1 < frames_data.len(), 1.0 - within_slice, 1.0)
+                    };
+                    let neighbour_share = if has_neighbour && fade_width > 0.0 {
+                        let t = (edge_distance / fade_width).clamp(0.0, 1.0);
+                        0.5 * (1.0 - t * t * (3.0 - 2.0 * t))
+                    } else {
+                        0.0
+                    };
+                    *pixel_slice = PixelSlice {
+                        slice: slice as u32,
+                        neighbour_share: direction * neighbour_share,
+                    };
+                }
+            });
+        let mut slice_order: Vec<u32> = (0..pixel_slices.len()).map(|index| (index % row_len) as u32).collect();
+        slice_order
+            .par_chunks_mut(row_len)
+            .zip(pixel_slices.par_chunks(row_len))
+            .for_each(|(row_order, row_slices)| row_order.sort_by_key(|x| row_slices[*x as usize].slice));
+
+        TimeSlice {
+            pixel_slices,
+            slice_order,
+            slice_count: frames_data.len(),
+            image: ImageBuffer::new(width, rows.len() as u32),
+            decoding_table: color_space.decoding_table(),
+            color_space,
+        }
+    }
+
+    /// The slices that show up in the rows, in slice order.
+    pub fn visible_slices(&self) -> Vec<usize> {
+        let mut visible = vec![false; self.slice_count];
+        for pixel_slice in &self.pixel_slices {
+            let slice = pixel_slice.slice as usize;
+            visible[slice] = true;
+            if pixel_slice.neighbour_share < 0.0 {
+                visible[slice - 1] = true;
+            } else if pixel_slice.neighbour_share > 0.0 {
+                visible[slice + 1] = true;
+            }
+        }
+        (0..self.slice_count).filter(|slice| visible[*slice]).collect()
+    }
+
+    /// Mixes the frame of a slice into the pixels it shows up in, `img` holds the rows of the time slice.
+    pub fn add_frame(&mut self, slice: usize, img: &RgbaImage) {
+        let row_len = self.image.width().max(1) as usize;
+        let decoding_table = &self.decoding_table;
+        self.image
+            .par_chunks_mut(row_len * 4)
+            .zip(img.par_chunks(row_len * 4))
+            .zip(self.pixel_slices.par_chunks(row_len).zip(self.slice_order.par_chunks(row_len)))
+            .for_each(|((out_row, frame_row), (row_slices, row_order))| {
+                // Only the pixels of this slice and its neighbours can mix in the frame
+                let first = row_order.partition_point(|x| (row_slices[*x as usize].slice as usize) + 1 < slice);
+                let last = row_order.partition_point(|x| row_slices[*x as usize].slice as usize <= slice + 1);
+                for x in &row_order[first..last] {
+                    let share = row_slices[*x as usize].share_of(slice);
+                    if share == 0.0 {
+                        continue;
+                    }
+                    let offset = *x as usize * 4;
+                    for channel in 0..3 {
+                        out_row[offset + channel] += decoding_table[frame_row[offset + channel] as usize] * share;
+                    }
+                    out_row[offset + 3] += frame_row[offset + 3] as f32 / 255.0 * share;
+                }
+            });
+    }
+
+    /// The linear light image of the rows.
+    pub fn finish(mut self) -> Rgba32FImage {
+        self.color_space.convert_to_linear(&mut self.image);
+        self.image
+    }
+}
+
+/**
+
+Renders a time slice, every pixel shows the frame its position along the layout falls into.
+Near the edges of a slice the frame is faded into the neighbouring slice, so each pixel mixes at
+most two frames. Only frames that show up in the rendered rows are decoded. Mixing happens in the
+blend color space.
+# Arguments
+- `frames_data`: The contributing frames in slice order, their weights are the slice widths and
+  must be positive, see `prepare_request`.
+- `layout`: How the slices are arranged.
+- `blend`: Share of a slice over which it fades into its neighbours.
+- `color_space`: The color space the frames are mixed in.
+- `(width, height)`: The dimensions of the whole image, slices are laid out over all of it.
+- `rows`: The rows to render, e.g. of a tile.
+- `load_rows`: Decodes the given rows of a frame by its frame number.
+# Returns
+- The linear light image of the rendered rows, or an error message.
+
+ */
+pub fn render_time_slice<L>(
+    frames_data: &[FrameData],
+    layout: SliceLayout,
+    blend: f32,
+    color_space: BlendColorSpace,
+    (width, height): (u32, u32),
+    rows: Range<u32>,
+    load_rows: L,
+) -> Result<Rgba32FImage, String>
+where
+    L: Fn(usize) -> Result<RgbaImage, String> + Sync,
+{
+    let row_count = rows.len() as u32;
+    let mut time_slice = TimeSlice::new(frames_data, layout, blend, color_space, (width, height), rows);
+    let visible_slices = time_slice.visible_slices();
+    let frame_numbers: Vec<usize> = visible_slices
+        .iter()
+        .map(|slice| frames_data[*slice].frame_number)
+        .collect();
+    stream_frames(&frame_numbers, (width, row_count), load_rows, |index, img| {
+        time_slice.add_frame(visible_slices[index], img)
+    })?;
+    Ok(time_slice.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::core::output::to_rgba8;
+    use crate::core::test_support::{frames_data, load_from};
+    use crate::models::RelativePoint;
+
+    fn solid_frames(values: &[u8]) -> Vec<RgbaImage> {
+        values
+            .iter()
+            .map(|value| ImageBuffer::from_pixel(6, 2, Rgba([*value, *value, *value, 255])))
+            .collect()
+    }
+
+    #[test]
+    fn slices_are_as_wide_as_their_weight() {
+        let frames = solid_frames(&[0, 100, 200]);
+        let load = load_from(&frames);
+        let img = render_time_slice(
+            &frames_data([1.0, 1.0, 4.0]),
+            SliceLayout::Vertical,
+            0.0,
+            BlendColorSpace::Srgb,
+            (6, 2),
+            0..2,
+            load,
+        )
+        .unwrap();
+        let row: Vec<u8> = to_rgba8(img).rows().nth(1).unwrap().map(|pixel| pixel.0[0]).collect();
+        assert_eq!(row, vec![0, 100, 200, 200, 200, 200]);
+    }
+
+    #[test]
+    fn blended_slices_fade_into_each_other() {
+        let frames = solid_frames(&[0, 255]);
+        let load = load_from(&frames);
+        // Slices run from the left edge to the right edge, the boundary lies in the middle
+        let layout = SliceLayout::Gradient {
+            start: RelativePoint { x: 0.0, y: 0.0 },
+            end: RelativePoint { x: 1.0, y: 0.0 },
+        };
+        let img = render_time_slice(&frames_data([1.0, 1.0]), layout, 1.0, BlendColorSpace::Linear, (6, 2), 0..2, load)
+            .unwrap();
+        let row: Vec<f32> = (0..6).map(|x| img.get_pixel(x, 0).0[0]).collect();
+        assert!(row.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!((row[0], row[5]), (0.0, 1.0));
+        // The pixels next to the boundary mix both frames equally far from half and half
+        assert!(row[2] > 0.0 && (row[2] + row[3] - 1.0).abs() < 1e-6);
+        assert_eq!(img.get_pixel(0, 0).0[3], 1.0);
+    }
+
+    #[test]
+    fn shares_of_every_pixel_add_up_to_one() {
+        let frames = solid_frames(&[0, 60, 120, 180, 240]);
+        let layout = SliceLayout::Radial {
+            center: RelativePoint { x: 0.3, y: 0.6 },
+        };
+        let img = render_time_slice(
+            &frames_data([1.0, 2.0, 0.5, 1.0, 3.0]),
+            layout,
+            0.8,
+            BlendColorSpace::Linear,
+            (6, 2),
+            0..2,
+            load_from(&frames),
+        )
+        .unwrap();
+        for pixel in img.pixels() {
+            assert!((pixel.0[3] - 1.0).abs() < 1e-6, "{:?}", pixel);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::models::{ColorKey, LumaCoefficients, WeightingCurve, WeightingSettings};
+
+impl LumaCoefficients {
+    /// Returns the luma of an RGB triple, in the same range as the input values.
+    pub fn luma(&self, r: f32, g: f32, b: f32) -> f32 {
+        //https://stackoverflow.com/questions/596216/formula-to-determine-perceived-brightness-of-rgb-color
+        match self {
+            LumaCoefficients::Rec601 => 0.299 * r + 0.587 * g + 0.114 * b,
+            LumaCoefficients::Rec709 => 0.2126 * r + 0.7152 * g + 0.0722 * b,
+        }
+    }
+}
+
+impl WeightingCurve {
+    /// Maps a brightness in `[0,1]` to a pixel weight in `[0,1]`.
+    pub fn weight(&self, brightness_norm: f32) -> f32 {
+        match *self {
+            WeightingCurve::None => 1.0,
+            WeightingCurve::Power { exponent } => brightness_norm.powf(exponent),
+            WeightingCurve::Threshold { threshold, knee } => {
+                if knee <= 0.0 {
+                    if brightness_norm >= threshold { 1.0 } else { 0.0 }
+                } else {
+                    ((brightness_norm - threshold) / knee).clamp(0.0, 1.0)
+                }
+            }
+            WeightingCurve::Smoothstep { low, high } => {
+                if high <= low {
+                    return if brightness_norm >= high { 1.0 } else { 0.0 };
+                }
+                let t = ((brightness_norm - low) / (high - low)).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            }
+        }
+    }
+}
+
+/// Saturation below `ColorKey::min_saturation` over which the boost fades out.
+const SATURATION_SOFTNESS: f32 = 0.05;
+
+impl ColorKey {
+    /// Share of the boost a color gets, 1 inside the ranges and fading to 0 outside of them.
+    fn membership(&self, hue: f32, saturation: f32) -> f32 {
+        let saturation_membership = if saturation < self.min_saturation {
+            1.0 - (self.min_saturation - saturation) / SATURATION_SOFTNESS
+        } else if saturation > self.max_saturation {
+            1.0 - (saturation - self.max_saturation) / SATURATION_SOFTNESS
+        } else {
+            1.0
+        };
+        if saturation_membership <= 0.0 {
+            return 0.0;
+        }
+
+        let span = self.hue_end - self.hue_start;
+        let width = span.rem_euclid(360.0);
+        let offset = (hue - self.hue_start).rem_euclid(360.0);
+        // Distance in degrees to the closer end of the range, zero inside of it. A span of a full
+        // turn or more covers every hue, even though its width wraps around to zero
+        let hue_distance = if span >= 360.0 || offset <= width {
+            0.0
+        } else {
+            (offset - width).min(360.0 - offset)
+        };
+        let hue_membership = if self.softness > 0.0 {
+            (1.0 - hue_distance / self.softness).max(0.0)
+        } else if hue_distance == 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+
+        saturation_membership * hue_membership
+    }
+}
+
+/// HSV hue in degrees and saturation of an 8-bit RGB color.
+fn hue_and_saturation(rgb: &[u8]) -> (f32, f32) {
+    let (r, g, b) = (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    if chroma == 0.0 {
+        return (0.0, 0.0);
+    }
+    let hue = if max == r {
+        60.0 * ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / chroma + 2.0)
+    } else {
+        60.0 * ((r - g) / chroma + 4.0)
+    };
+    (hue, chroma / max)
+}
+
+/// Number of segments the power curve is tabulated with. The linear interpolation between them
+/// is far below the precision of 8-bit frames.
+const POWER_TABLE_SEGMENTS: usize = 4096;
+
+/// Weighting settings prepared for evaluating them per pixel. The power curve is looked up in a
+/// table instead of calling `powf` for every pixel of every frame.
+#[derive(Debug, Clone)]
+pub struct PixelWeighting {
+    curve: WeightingCurve,
+    luma: LumaCoefficients,
+    color_keys: Vec<ColorKey>,
+    power_table: Option<Vec<f32>>,
+}
+
+impl WeightingSettings {
+    pub fn prepare(&self) -> PixelWeighting {
+        let power_table = match self.curve {
+            WeightingCurve::Power { .. } => Some(
+                (0..=POWER_TABLE_SEGMENTS)
+                    .map(|segment| self.curve.weight(segment as f32 / POWER_TABLE_SEGMENTS as f32))
+                    .collect(),
+            ),
+            _ => None,
+        };
+        PixelWeighting {
+            curve: self.curve,
+            luma: self.luma,
+            color_keys: self.color_keys.clone(),
+            power_table,
+        }
+    }
+}
+
+impl PixelWeighting {
+    /// Weight of a pixel given its 8-bit RGB values, including the boost of the color keys.
+    #[inline]
+    pub fn pixel_weight(&self, rgb: &[u8]) -> f32 {
+        self.brightness_weight(rgb) * self.key_boost(rgb)
+    }
+
+    /// Product of the boosts of all color keys matching the color, 1 without keys.
+    #[inline]
+    pub fn key_boost(&self, rgb: &[u8]) -> f32 {
+        if self.color_keys.is_empty() {
+            return 1.0;
+        }
+        let (hue, saturation) = hue_and_saturation(rgb);
+        self.color_keys
+            .iter()
+            .map(|key| 1.0 + (key.boost - 1.0) * key.membership(hue, saturation))
+            .product()
+    }
+
+    fn brightness_weight(&self, rgb: &[u8]) -> f32 {
+        if self.curve == WeightingCurve::None {
+            return 1.0;
+        }
+        let brightness = self.luma.luma(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+        let brightness_norm = brightness / 255.0; // Normalize to [0,1]
+        match &self.power_table {
+            Some(table) => {
+                let position = brightness_norm.clamp(0.0, 1.0) * POWER_TABLE_SEGMENTS as f32;
+                let segment = (position as usize).min(POWER_TABLE_SEGMENTS - 1);
+                let t = position - segment as f32;
+                table[segment] + (table[segment + 1] - table[segment]) * t
+            }
+            None => self.curve.weight(brightness_norm),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_table_follows_the_curve() {
+        let settings = WeightingSettings {
+            curve: WeightingCurve::Power { exponent: 4.5 },
+            luma: LumaCoefficients::Rec709,
+            color_keys: Vec::new(),
+        };
+        let weighting = settings.prepare();
+        for value in [0u8, 1, 17, 128, 200, 254, 255] {
+            let rgb = [value, value.saturating_sub(30), value / 2];
+            let brightness_norm = LumaCoefficients::Rec709.luma(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32) / 255.0;
+            let expected = settings.curve.weight(brightness_norm);
+            assert!((weighting.pixel_weight(&rgb) - expected).abs() < 1e-6, "{}", value);
+        }
+    }
+
+    #[test]
+    fn color_keys_boost_matching_hues() {
+        let red_key = ColorKey {
+            hue_start: 340.0,
+            hue_end: 20.0,
+            min_saturation: 0.4,
+            max_saturation: 1.0,
+            boost: 4.0,
+            softness: 10.0,
+        };
+        let weighting = WeightingSettings {
+            curve: WeightingCurve::None,
+            luma: LumaCoefficients::Rec601,
+            color_keys: vec![red_key],
+        }
+        .prepare();
+
+        assert_eq!(weighting.pixel_weight(&[250, 10, 30]), 4.0);
+        assert_eq!(weighting.pixel_weight(&[255, 255, 255]), 1.0);
+        assert_eq!(weighting.pixel_weight(&[20, 40, 250]), 1.0);
+        // 25 degrees is halfway through the soft edge
+        let (hue, _) = hue_and_saturation(&[255, 106, 0]);
+        assert!((hue - 25.0).abs() < 0.1);
+        assert!((weighting.pixel_weight(&[255, 106, 0]) - 2.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn full_hue_circle_matches_every_hue() {
+        for hue_end in [360.0, 400.0] {
+            let key = ColorKey {
+                hue_start: 0.0,
+                hue_end,
+                min_saturation: 0.0,
+                max_saturation: 1.0,
+                boost: 2.0,
+                softness: 0.0,
+            };
+            for hue in [0.0, 90.0, 180.0, 359.0] {
+                assert_eq!(key.membership(hue, 0.8), 1.0, "{} of 0..{}", hue, hue_end);
+            }
+        }
+    }
+}
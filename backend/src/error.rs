@@ -1,3 +1,4 @@
+use serde::Serialize;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -5,9 +6,11 @@ pub enum ServiceError {
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Image creation error: {0}")]
-    CreateImageError(String),
+    CreateImageError(#[from] RenderError),
     #[error("Metadata error: {0}")]
     MetadataError(#[from] MetadataError),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
     #[error("Other error: {0}")]
     Other(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
@@ -18,4 +21,38 @@ pub enum MetadataError {
     IoError(#[from] std::io::Error),
     #[error("Could not deserialize metadata: {0}")]
     SerdeError(#[from] serde_json::Error),
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("{} frame(s) do not have the expected dimensions {expected:?}: {}", frames.len(), describe_mismatched_frames(frames))]
+    DimensionMismatch {
+        expected: (u32, u32),
+        frames: Vec<MismatchedFrame>,
+    },
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+impl From<String> for RenderError {
+    fn from(message: String) -> Self {
+        RenderError::Failed(message)
+    }
+}
+
+/// A frame whose dimensions differ from the first frame of a render.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MismatchedFrame {
+    pub frame_number: usize,
+    pub dimensions: (u32, u32),
+}
+
+fn describe_mismatched_frames(frames: &[MismatchedFrame]) -> String {
+    frames
+        .iter()
+        .map(|frame| format!("frame {} is {}x{}", frame.frame_number, frame.dimensions.0, frame.dimensions.1))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
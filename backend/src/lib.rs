@@ -0,0 +1,6 @@
+pub mod core;
+pub mod controller;
+pub mod utils;
+pub mod services;
+pub mod error;
+pub mod models;
@@ -2,14 +2,8 @@ use actix_cors::Cors;
 use actix_files as fs;
 use actix_web::{App, HttpServer};
 
-use crate::utils::{create_directory_if_not_created_yet, get_output_dir, get_upload_dir};
-
-mod core;
-mod controller;
-mod utils;
-mod services;
-mod error;
-mod models;
+use rust_beiboot::controller;
+use rust_beiboot::utils::{create_directory_if_not_created_yet, get_output_dir, get_upload_dir};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -34,9 +28,12 @@ async fn main() -> std::io::Result<()> {
             .service(fs::Files::new("/uploads", upload_dir.to_str().unwrap()).show_files_listing())
             .service(controller::projects::create_or_update_project)
             .service(controller::projects::create_long_exposure_image)
+            .service(controller::projects::create_long_exposure_image_variants)
             .service(controller::projects::get_projects)
             .service(controller::projects::get_project_metadata)
             .service(controller::projects::delete_project)
+            .service(controller::projects::upload_mask)
+            .service(controller::projects::get_masks)
     })
     .bind(("0.0.0.0", 8081))?
     .run()
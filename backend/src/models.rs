@@ -14,22 +14,718 @@ pub struct ProjectMetadata {
     pub scale: String,
     pub video_file_extension: String,
     pub latest_long_exposure_image_name: Option<String>,
+    /// Color space renders of this project blend in unless the request overrides it.
+    /// Projects created before linear blending existed keep blending in sRGB.
+    #[serde(default = "BlendColorSpace::legacy")]
+    pub blend_color_space: BlendColorSpace,
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FrameData {
     pub frame_number: usize,
     pub frame_weight: f32,
+    /// Composites the moving subject of this frame sharp on top of the long exposure,
+    /// like a rear-curtain flash. At most one frame can be frozen.
+    #[serde(default)]
+    pub freeze: bool,
 }
-#[derive(Deserialize)]
+
+/// How the selected frames are combined into the long exposure image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    /// Brightness weighted average of all frames (smooth water, clouds).
+    #[default]
+    WeightedMean,
+    /// Keeps the brightest value per pixel (light trails).
+    Lighten,
+    /// Keeps the darkest value per pixel.
+    Darken,
+    /// Weighted temporal median per pixel (removes moving objects / ghosts).
+    Median,
+    /// Inverse multiply of all frames, brightens like stacked projectors.
+    Screen,
+    /// Sums all frames, scaled by their weight.
+    Additive,
+    /// Keeps the brightest value per pixel like `Lighten`, but frames fade with their distance
+    /// to the newest (or oldest) frame, so trails taper off like a comet tail.
+    /// The opacity halves every `half_life` frames, a half-life of zero disables the fade.
+    LightTrails {
+        #[serde(default = "default_trail_half_life")]
+        half_life: f32,
+        #[serde(default)]
+        fade: TrailFade,
+    },
+    /// Averages each pixel after iteratively discarding samples more than `kappa` standard
+    /// deviations away from the `center` of the samples (sigma / kappa clipping). Removes
+    /// passers-by or flashes that only show up in a few frames.
+    SigmaClipping {
+        #[serde(default = "default_clipping_kappa")]
+        kappa: f32,
+        #[serde(default = "default_clipping_iterations")]
+        iterations: u32,
+        #[serde(default)]
+        center: ClippingCenter,
+    },
+    /// Sequence shot: the moving subject of every `every`th frame is shown sharp on top of a
+    /// clean background plate, the weighted median of all frames. The subjects are separated by
+    /// their difference to the plate like the frozen frame and composited in frame order with
+    /// `opacity`, scaled by their weight relative to the strongest frame.
+    Stroboscopic {
+        #[serde(default = "default_strobe_every")]
+        every: usize,
+        #[serde(default = "default_strobe_opacity")]
+        opacity: f32,
+        /// Difference to the plate from which on a pixel fully belongs to the subject, see `FreezeFrameSettings`.
+        #[serde(default = "default_strobe_threshold")]
+        threshold: f32,
+        /// Radius in pixels the subject edges are softened with.
+        #[serde(default = "default_strobe_feather")]
+        feather: f32,
+    },
+    /// Time slice / slit scan: every pixel shows a single frame chosen by its position in the
+    /// image, the first frame at the start of the `layout` and the last one at its end. The
+    /// slices are as wide as the weights of their frames relative to the total weight, so the
+    /// weights must be positive.
+    TimeSlice {
+        #[serde(default)]
+        layout: SliceLayout,
+        /// Share of a slice in `[0,1]` over which it fades into its neighbours, 0 gives hard edges.
+        #[serde(default)]
+        blend: f32,
+    },
+}
+
+/// How the frames of a time slice are arranged in the image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SliceLayout {
+    /// Vertical slices from left to right.
+    #[default]
+    Vertical,
+    /// Horizontal slices from top to bottom.
+    Horizontal,
+    /// Rings from `center` out to the farthest corner.
+    Radial {
+        #[serde(default)]
+        center: RelativePoint,
+    },
+    /// Slices perpendicular to the line from `start` to `end`, pixels beyond its ends show
+    /// the first or last frame.
+    Gradient { start: RelativePoint, end: RelativePoint },
+}
+
+/// A point in the rendered image, `(0,0)` is the top left and `(1,1)` the bottom right corner.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RelativePoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Default for RelativePoint {
+    fn default() -> Self {
+        RelativePoint { x: 0.5, y: 0.5 }
+    }
+}
+
+fn default_trail_half_life() -> f32 {
+    10.0
+}
+
+fn default_strobe_every() -> usize {
+    5
+}
+
+fn default_strobe_opacity() -> f32 {
+    1.0
+}
+
+fn default_strobe_threshold() -> f32 {
+    FreezeFrameSettings::default().threshold
+}
+
+fn default_strobe_feather() -> f32 {
+    FreezeFrameSettings::default().feather
+}
+
+fn default_clipping_kappa() -> f32 {
+    2.5
+}
+
+fn default_clipping_iterations() -> u32 {
+    3
+}
+
+/// Statistic the samples of a pixel are compared to when clipping outliers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClippingCenter {
+    /// Weighted mean with the standard deviation as spread.
+    #[default]
+    Mean,
+    /// Weighted median with the scaled median absolute deviation as spread, which is not pulled
+    /// towards the outliers when only few frames are stacked.
+    Median,
+}
+
+/// End of the frame selection the light trails fade toward.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailFade {
+    /// The first frames are the faintest, trails end at the last frame at full brightness.
+    #[default]
+    TowardFirst,
+    /// The last frames are the faintest.
+    TowardLast,
+}
+
+/// Maps the normalized brightness of a pixel to its weight in the weighted mean.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WeightingCurve {
+    /// Every pixel counts the same, a plain average.
+    None,
+    /// `brightness ^ exponent`, higher exponents favour highlights more strongly.
+    Power { exponent: f32 },
+    /// Pixels below `threshold` are ignored, above it they ramp up to full weight over `knee`.
+    Threshold { threshold: f32, knee: f32 },
+    /// Smooth Hermite ramp from zero weight at `low` to full weight at `high`.
+    Smoothstep { low: f32, high: f32 },
+}
+
+impl Default for WeightingCurve {
+    fn default() -> Self {
+        WeightingCurve::Power { exponent: 4.5 }
+    }
+}
+
+/// Coefficients used to compute the brightness (luma) of a pixel.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LumaCoefficients {
+    #[default]
+    Rec601,
+    Rec709,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct WeightingSettings {
+    #[serde(default)]
+    pub curve: WeightingCurve,
+    #[serde(default)]
+    pub luma: LumaCoefficients,
+    /// Colors whose pixels get more (or less) weight than their brightness alone would give them.
+    /// The boosts of all matching keys are multiplied.
+    #[serde(default)]
+    pub color_keys: Vec<ColorKey>,
+}
+
+/// Boosts pixels of a hue and saturation range, e.g. red tail lights or blue emergency lights.
+/// The weighted mean multiplies the pixel weight with the boost, the other streamable modes
+/// the opacity of the pixel.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ColorKey {
+    /// Start of the hue range in degrees.
+    pub hue_start: f32,
+    /// End of the hue range in degrees, the range wraps around 360 if it is below `hue_start`
+    /// (e.g. from 340 to 20 for red). A range of 360 degrees or more matches every hue.
+    pub hue_end: f32,
+    /// Smallest HSV saturation in `[0,1]` that is boosted, keeps neutral highlights out.
+    #[serde(default = "default_color_key_min_saturation")]
+    pub min_saturation: f32,
+    #[serde(default = "default_color_key_max_saturation")]
+    pub max_saturation: f32,
+    /// Factor the weight of matching pixels is multiplied with.
+    pub boost: f32,
+    /// Degrees outside of the hue range over which the boost fades out.
+    #[serde(default = "default_color_key_softness")]
+    pub softness: f32,
+}
+
+fn default_color_key_min_saturation() -> f32 {
+    0.4
+}
+
+fn default_color_key_max_saturation() -> f32 {
+    1.0
+}
+
+fn default_color_key_softness() -> f32 {
+    10.0
+}
+
+/// Color space the frames are blended in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendColorSpace {
+    /// Frames are converted to linear light before blending and re-encoded to sRGB afterwards,
+    /// which keeps highlights and colors like a real long exposure would.
+    #[default]
+    Linear,
+    /// Blends the 8-bit sRGB values directly, like renders before linear blending existed.
+    Srgb,
+}
+
+impl BlendColorSpace {
+    pub fn legacy() -> Self {
+        BlendColorSpace::Srgb
+    }
+}
+
+/// File format the long exposure image is written in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// 8 bits per channel PNG.
+    #[default]
+    Png,
+    /// 16 bits per channel PNG, avoids banding when grading the result.
+    Png16,
+    /// 16 bits per channel TIFF.
+    Tiff16,
+    /// OpenEXR with 32-bit float linear light channels, values above 1 are kept.
+    Exr,
+    /// JPEG with `quality` from 1 to 100, drops the alpha channel.
+    Jpeg {
+        #[serde(default = "default_output_quality")]
+        quality: u8,
+    },
+    /// WebP, lossy with `quality` from 0 to 100 unless `lossless` is set.
+    Webp {
+        #[serde(default)]
+        lossless: bool,
+        #[serde(default = "default_output_quality")]
+        quality: u8,
+    },
+    /// AVIF with `quality` from 1 to 100 and encoder `speed` from 1 (slow, small) to 10 (fast).
+    Avif {
+        #[serde(default = "default_output_quality")]
+        quality: u8,
+        #[serde(default = "default_avif_speed")]
+        speed: u8,
+    },
+}
+
+fn default_output_quality() -> u8 {
+    85
+}
+
+fn default_avif_speed() -> u8 {
+    6
+}
+
+/// Maps linear light values, which can exceed 1 e.g. in additive mode, into the displayable range.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToneMapping {
+    /// Values are clipped to 1 when writing an integer format, EXR keeps them untouched.
+    #[default]
+    Clip,
+    /// Scales the image by `2^stops` and clips afterwards.
+    Exposure { stops: f32 },
+    /// Reinhard operator on the luminance. Luminance at `white_point` and above maps to white,
+    /// without a white point highlights approach white asymptotically.
+    Reinhard { white_point: Option<f32> },
+    /// Filmic S-curve (ACES approximation) with a soft highlight roll-off.
+    Filmic,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct OutputSettings {
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default)]
+    pub tone_mapping: ToneMapping,
+    /// Scales the image to this width, keeping the aspect ratio unless `height` is set as well.
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Scales the image to this height, keeping the aspect ratio unless `width` is set as well.
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// Downscales the image so that its longer side is at most this many pixels.
+    #[serde(default)]
+    pub max_dimension: Option<u32>,
+}
+
+/// Motion model the frames are registered with before blending.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlignmentMethod {
+    /// Shifts the frames, enough for a steadily held camera.
+    #[default]
+    Translation,
+    /// Additionally searches small rotations of the camera around the image center.
+    TranslationRotation,
+}
+
+/// Registers every frame against a reference frame before it is blended, which removes the
+/// ghosting caused by a handheld camera.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AlignmentSettings {
+    #[serde(default)]
+    pub method: AlignmentMethod,
+    /// Frame the others are aligned to, the first selected frame if not set.
+    #[serde(default)]
+    pub reference_frame: Option<usize>,
+    /// Largest rotation in degrees that is searched for by `TranslationRotation`.
+    #[serde(default = "default_max_rotation_degrees")]
+    pub max_rotation_degrees: f32,
+}
+
+fn default_max_rotation_degrees() -> f32 {
+    2.0
+}
+
+/// Restricts the long exposure blend to the white areas of a mask uploaded to the project,
+/// the black areas show a single key frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaskSettings {
+    /// Name the mask was uploaded with.
+    pub name: String,
+    /// Frame shown outside the mask, the first selected frame if not set.
+    #[serde(default)]
+    pub key_frame: Option<usize>,
+    /// Radius in pixels the mask edges are softened with.
+    #[serde(default = "default_mask_feather")]
+    pub feather: f32,
+    /// Blends outside the mask instead of inside.
+    #[serde(default)]
+    pub invert: bool,
+}
+
+fn default_mask_feather() -> f32 {
+    8.0
+}
+
+/// How the subject of the frozen frame is separated from the long exposure background.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct FreezeFrameSettings {
+    /// Difference to the background from which on a pixel fully belongs to the subject, in `[0,1]`.
+    /// Pixels with half of this difference or less stay background.
+    pub threshold: f32,
+    /// Radius in pixels the subject edges are softened with.
+    pub feather: f32,
+}
+
+impl Default for FreezeFrameSettings {
+    fn default() -> Self {
+        FreezeFrameSettings {
+            threshold: 0.12,
+            feather: 3.0,
+        }
+    }
+}
+
+/// What happens to frames whose dimensions differ from the first selected frame.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DimensionPolicy {
+    /// The render fails with an error naming the frames.
+    #[default]
+    Reject,
+    /// Frames are scaled to the reference dimensions, the aspect ratio is not kept.
+    Resample,
+    /// Frames are centered on the reference, overhanging parts are cut off and uncovered
+    /// parts do not contribute.
+    Crop,
+}
+
+/// Part of the frames that is rendered, in pixels of the full size frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CropRectangle {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Clockwise rotation of the rendered image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+/// Every `step`-th frame from `start` to `end`, both inclusive.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FrameRange {
+    pub start: usize,
+    pub end: usize,
+    #[serde(default = "default_frame_range_step")]
+    pub step: usize,
+}
+
+fn default_frame_range_step() -> usize {
+    1
+}
+
+/// How the weight changes from a keyframe to the next one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyframeInterpolation {
+    #[default]
+    Linear,
+    /// Starts and ends slowly (smoothstep).
+    EaseInOut,
+    /// Keeps the weight until the next keyframe.
+    Hold,
+}
+
+/// Weight of the frames in `frame_ranges` at a frame number.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WeightKeyframe {
+    pub frame: usize,
+    pub weight: f32,
+    #[serde(default)]
+    pub interpolation: KeyframeInterpolation,
+}
+
+/// A post-processing step applied to the rendered image after blending and before it is encoded.
+/// Steps work on linear light, levels and curves on sRGB encoded values like in image editors.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Adjustment {
+    /// Brightens (positive) or darkens the image by `ev` stops.
+    Exposure { ev: f32 },
+    /// Stretches the encoded range from `black_point` to `white_point` to the full range and bends
+    /// the midtones with `gamma`, values above 1 brighten them.
+    Levels {
+        #[serde(default)]
+        black_point: f32,
+        #[serde(default = "default_levels_white_point")]
+        white_point: f32,
+        #[serde(default = "default_levels_gamma")]
+        gamma: f32,
+    },
+    /// Tone curve through points of encoded input and output values in `[0,1]`, interpolated
+    /// without overshooting between them.
+    Curve { points: Vec<CurvePoint> },
+    /// Scales the colorfulness, 0 gives a grayscale image and 1 leaves it unchanged.
+    Saturation { amount: f32 },
+    /// Warms (positive `temperature`) or cools the image and shifts it towards magenta (positive
+    /// `tint`) or green, both in stops. The overall brightness is kept.
+    WhiteBalance {
+        #[serde(default)]
+        temperature: f32,
+        #[serde(default)]
+        tint: f32,
+    },
+    /// Sharpens by adding `amount` times the difference to a copy blurred with `radius` pixels.
+    /// Differences up to `threshold` are left alone, so noise is not sharpened.
+    UnsharpMask {
+        radius: f32,
+        amount: f32,
+        #[serde(default)]
+        threshold: f32,
+    },
+    /// Darkens (negative `amount`) or brightens the corners. The effect starts at `midpoint`,
+    /// the share of the distance from the center to the corners.
+    Vignette {
+        amount: f32,
+        #[serde(default = "default_vignette_midpoint")]
+        midpoint: f32,
+    },
+}
+
+fn default_levels_white_point() -> f32 {
+    1.0
+}
+
+fn default_levels_gamma() -> f32 {
+    1.0
+}
+
+fn default_vignette_midpoint() -> f32 {
+    0.5
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CurvePoint {
+    pub input: f32,
+    pub output: f32,
+}
+
+/// An animation rendered from the frame selection in addition to the still image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AnimationSettings {
+    #[serde(flatten)]
+    pub kind: Animation,
+    #[serde(default)]
+    pub format: AnimationFormat,
+    /// Frames per second of the animation, defaults to the frame rate the frames were extracted with.
+    #[serde(default)]
+    pub fps: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Animation {
+    /// Every animation frame blends the last `window` selected frames, a sliding long exposure.
+    Rolling {
+        window: usize,
+        /// Number of selected frames the window moves per animation frame.
+        #[serde(default = "default_rolling_step")]
+        step: usize,
+    },
+    /// Shows the long exposure developing as the selected frames are added one after another,
+    /// with an animation frame every `every` frames. The last animation frame is the still image.
+    BuildUp {
+        #[serde(default = "default_build_up_every")]
+        every: usize,
+    },
+}
+
+fn default_rolling_step() -> usize {
+    1
+}
+
+fn default_build_up_every() -> usize {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AnimationFormat {
+    #[default]
+    Mp4,
+    Webm,
+    Gif,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CreateLongExposureImageRequest {
+    /// Frames listed one by one. Together with `frame_ranges` they override the weight of
+    /// single frames.
+    #[serde(default)]
     pub frames_to_include: Vec<FrameData>,
+    /// Ranges of frames to include, weighted by `weight_keyframes`.
+    #[serde(default)]
+    pub frame_ranges: Vec<FrameRange>,
+    /// Weights of the frames in `frame_ranges`, interpolated between the keyframes.
+    #[serde(default)]
+    pub weight_keyframes: Vec<WeightKeyframe>,
+    #[serde(default)]
+    pub blend_mode: BlendMode,
+    #[serde(default)]
+    pub weighting: WeightingSettings,
+    /// Overrides the blend color space of the project.
+    #[serde(default)]
+    pub color_space: Option<BlendColorSpace>,
+    #[serde(default)]
+    pub output: OutputSettings,
+    /// Aligns the frames to each other before blending, off if not set.
+    #[serde(default)]
+    pub alignment: Option<AlignmentSettings>,
+    /// Blends only inside a project mask, off if not set.
+    #[serde(default)]
+    pub mask: Option<MaskSettings>,
+    /// Settings for the frame marked with `freeze` in `frames_to_include`.
+    #[serde(default)]
+    pub freeze_frame: FreezeFrameSettings,
+    /// Blends the low resolution frame thumbnails instead of the full size frames. Previews are
+    /// stored apart from the final renders and do not replace the latest image of the project.
+    #[serde(default)]
+    pub preview: bool,
+    /// Handling of frames whose dimensions differ from the first selected frame.
+    #[serde(default)]
+    pub dimension_policy: DimensionPolicy,
+    /// Renders in horizontal tiles of this many rows, which bounds the memory needed for very
+    /// large frames. The result is identical to an untiled render, off if not set. Frames must be
+    /// read unmodified, so it cannot be combined with alignment, previews or resampling, nor with
+    /// the stroboscopic blend mode.
+    #[serde(default)]
+    pub tile_rows: Option<u32>,
+    /// Renders only this part of the frames, off if not set.
+    #[serde(default)]
+    pub crop: Option<CropRectangle>,
+    /// Rotates the rendered image before the adjustments are applied.
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Post-processing steps, applied in the given order.
+    #[serde(default)]
+    pub adjustments: Vec<Adjustment>,
+    /// Renders an animation with the same blend settings as well.
+    #[serde(default)]
+    pub animation: Option<AnimationSettings>,
+}
+
+/// Several renders of the same frames that only differ in some settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateLongExposureImageVariantsRequest {
+    /// The settings every variant starts from.
+    pub base: CreateLongExposureImageRequest,
+    pub variants: Vec<RenderVariant>,
+    /// Whether a sheet with a labelled thumbnail of every variant is rendered as well.
+    #[serde(default)]
+    pub contact_sheet: bool,
+}
+
+/// Changes of one variant to the base request, merged into it like a JSON merge patch:
+/// objects are merged field by field, `null` removes a field and everything else replaces it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenderVariant {
+    /// Shown on the contact sheet, defaults to the position of the variant.
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(flatten)]
+    pub changes: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Number of samples that were discarded as outliers by `BlendMode::SigmaClipping`.
+/// Samples are counted per color channel.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct RejectionStatistics {
+    pub rejected_samples: u64,
+    pub total_samples: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateLongExposureImageResponse {
+    pub image_url: String,
+    /// Only set for blend modes that reject samples.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<RejectionStatistics>,
+    /// Only set if the request asked for an animation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub animation_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateLongExposureImageVariantsResponse {
+    /// The variants in the order of the request.
+    pub variants: Vec<VariantResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact_sheet_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantResponse {
+    pub label: String,
+    pub image_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<RejectionStatistics>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct GetProjectsResponse {
     pub projects: Vec<Project>,
 }
+#[derive(Debug, Serialize)]
+pub struct UploadMaskResponse {
+    pub message: &'static str,
+    pub mask_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GetMasksResponse {
+    pub masks: Vec<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UploadVideoResponse {
     pub message: &'static str,
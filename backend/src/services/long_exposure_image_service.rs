@@ -1,32 +1,129 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
 use tracing::log::trace;
 
+use crate::core::batch::create_long_exposure_image_variants;
+use crate::core::incremental::AccumulationState;
 use crate::core::long_exposure_image_logic::create_long_exposure_image;
 use crate::error::ServiceError;
-use crate::models::FrameData;
+use crate::models::{
+    CreateLongExposureImageRequest, CreateLongExposureImageResponse, CreateLongExposureImageVariantsRequest,
+    CreateLongExposureImageVariantsResponse,
+};
 use crate::services::long_exposure_image_service::ServiceError::CreateImageError;
 use crate::utils::{get_output_dir, read_metadata_from_project, save_project_metadata};
 
+/// Accumulation buffers are as large as a few full resolution frames, so only the
+/// most recently rendered projects keep them. Previews are limited separately.
+const MAX_CACHED_ACCUMULATION_STATES: usize = 2;
+
+/// Project id and whether the render was a preview.
+type AccumulationStateKey = (String, bool);
+
+/// Accumulation state of the latest render and latest preview per project id, used to only
+/// re-render the frames that changed when the next request of a project comes in.
+static ACCUMULATION_STATES: OnceLock<
+    Mutex<HashMap<AccumulationStateKey, (Instant, AccumulationState)>>,
+> = OnceLock::new();
+
 pub async fn create_long_exposure_image_svc(
     project_id: String,
-    frames_to_include: Vec<FrameData>,
-) -> Result<String, ServiceError> {
+    mut image_request: CreateLongExposureImageRequest,
+) -> Result<CreateLongExposureImageResponse, ServiceError> {
     let output_dir = get_output_dir();
     let path_to_cut_images = output_dir.join(format!("{}/frames/", project_id));
 
-    trace!("Frames to include are: {:?}", frames_to_include);
-
-    let path_to_long_exposure_img = create_long_exposure_image(path_to_cut_images, frames_to_include)
-        .await
-        .map_err(CreateImageError)?;
+    trace!("Frames to include are: {:?}", image_request.frames_to_include);
 
     let mut metadata = read_metadata_from_project(&project_id).await?;
 
-    metadata.latest_long_exposure_image_name = Some(path_to_long_exposure_img.clone());
+    // Animations play at the frame rate of the extraction unless they set their own
+    if let Some(animation) = &mut image_request.animation {
+        animation.fps.get_or_insert(metadata.fps as u32);
+    }
+
+    // The state is taken out of the cache while rendering, a concurrent render of the same
+    // project simply starts from scratch
+    let state_key = (project_id.clone(), image_request.preview);
+    let previous_state = take_accumulation_state(&state_key);
+    let preview = image_request.preview;
+
+    let (response, accumulation_state) = create_long_exposure_image(
+        path_to_cut_images,
+        image_request,
+        metadata.blend_color_space,
+        previous_state,
+    )
+    .await
+    .map_err(CreateImageError)?;
+
+    if let Some(state) = accumulation_state {
+        store_accumulation_state(state_key, state);
+    }
+
+    // Previews never replace the final render of the project
+    if !preview {
+        metadata.latest_long_exposure_image_name = Some(response.image_url.clone());
+
+        // Save metadata asynchronously
+        save_project_metadata(&metadata, &project_id)?;
+    }
+
+    Ok(response)
+}
+
+/// Renders several variants of a request at once. Variants are for comparing settings, so they
+/// never become the final render of the project.
+pub async fn create_long_exposure_image_variants_svc(
+    project_id: String,
+    variants_request: CreateLongExposureImageVariantsRequest,
+) -> Result<CreateLongExposureImageVariantsResponse, ServiceError> {
+    let output_dir = get_output_dir();
+    let path_to_cut_images = output_dir.join(format!("{}/frames/", project_id));
+
+    let metadata = read_metadata_from_project(&project_id).await?;
 
-    // Save metadata asynchronously
-    save_project_metadata(&metadata, &project_id)?;
+    create_long_exposure_image_variants(path_to_cut_images, variants_request, metadata.blend_color_space)
+        .await
+        .map_err(CreateImageError)
+}
+
+/// Drops the cached accumulation states of a project, e.g. because its frames were re-extracted.
+pub fn invalidate_accumulation_state(project_id: &str) {
+    let mut states = accumulation_states().lock().unwrap();
+    states.retain(|(id, _), _| id != project_id);
+}
 
-    Ok(path_to_long_exposure_img)
+fn accumulation_states(
+) -> &'static Mutex<HashMap<AccumulationStateKey, (Instant, AccumulationState)>> {
+    ACCUMULATION_STATES.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
+fn take_accumulation_state(key: &AccumulationStateKey) -> Option<AccumulationState> {
+    accumulation_states()
+        .lock()
+        .unwrap()
+        .remove(key)
+        .map(|(_, state)| state)
+}
 
+fn store_accumulation_state(key: AccumulationStateKey, state: AccumulationState) {
+    let mut states = accumulation_states().lock().unwrap();
+    let preview = key.1;
+    while states.keys().filter(|(_, other_preview)| *other_preview == preview).count()
+        >= MAX_CACHED_ACCUMULATION_STATES
+    {
+        let Some(oldest_key) = states
+            .iter()
+            .filter(|((_, other_preview), _)| *other_preview == preview)
+            .min_by_key(|(_, (last_used, _))| *last_used)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        states.remove(&oldest_key);
+    }
+    states.insert(key, (Instant::now(), state));
+}
@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use tokio::fs;
+
+use crate::core::compositing::{is_valid_mask_name, mask_file_name};
+use crate::error::ServiceError;
+use crate::utils::get_output_dir;
+
+/**
+
+Stores a mask in the project directory so renders of the project can refer to it by name.
+The mask is converted to 8-bit grayscale, an existing mask with the same name is replaced.
+# Arguments
+- `project_id`: The project the mask belongs to.
+- `mask_name`: Letters, digits, `-` and `_` only, as the name ends up in the file name.
+- `data`: The encoded mask image in any supported image format.
+# Returns
+- The name of the stored mask.
+
+ */
+pub async fn save_project_mask(
+    project_id: &str,
+    mask_name: &str,
+    data: &[u8],
+) -> Result<String, ServiceError> {
+    if !is_valid_mask_name(mask_name) {
+        return Err(ServiceError::InvalidInput(format!(
+            "Invalid mask name '{}', only letters, digits, '-' and '_' are allowed",
+            mask_name
+        )));
+    }
+
+    let project_dir_path = project_dir(project_id).await?;
+    let mask = image::load_from_memory(data)
+        .map_err(|e| ServiceError::InvalidInput(format!("Could not decode mask: {}", e)))?
+        .to_luma8();
+    let mask_path = project_dir_path.join(mask_file_name(mask_name));
+    mask.save(&mask_path)
+        .map_err(|e| ServiceError::Other(Box::new(e)))?;
+
+    Ok(mask_name.to_string())
+}
+
+/// Names of the masks stored for a project, sorted alphabetically.
+pub async fn list_project_masks(project_id: &str) -> Result<Vec<String>, ServiceError> {
+    let project_dir_path = project_dir(project_id).await?;
+    let mut masks = Vec::new();
+    let mut dir_entries = fs::read_dir(&project_dir_path).await?;
+    while let Some(entry) = dir_entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let mask_name = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix("mask_"))
+            .and_then(|name| name.strip_suffix(".png"));
+        if let Some(mask_name) = mask_name.filter(|name| is_valid_mask_name(name)) {
+            masks.push(mask_name.to_string());
+        }
+    }
+    masks.sort();
+    Ok(masks)
+}
+
+async fn project_dir(project_id: &str) -> Result<PathBuf, ServiceError> {
+    let project_dir_path = get_output_dir().join(project_id);
+    if !fs::try_exists(project_dir_path.join("metadata.json")).await? {
+        return Err(ServiceError::from(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Project not found",
+        )));
+    }
+    Ok(project_dir_path)
+}
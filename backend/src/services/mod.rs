@@ -1,2 +1,3 @@
 pub mod long_exposure_image_service;
+pub mod mask_service;
 pub mod projects_service;
\ No newline at end of file
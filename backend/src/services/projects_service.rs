@@ -8,7 +8,8 @@ use tracing::log::{debug, info};
 use uuid::Uuid;
 
 use crate::error::ServiceError;
-use crate::models::{Project, ProjectMetadata, UploadVideoResponse};
+use crate::models::{BlendColorSpace, Project, ProjectMetadata, UploadVideoResponse};
+use crate::services::long_exposure_image_service::invalidate_accumulation_state;
 use crate::utils::{convert_image_path_to_serving_url, get_output_dir, get_upload_dir, read_metadata_from_project, save_project_metadata};
 
 pub async fn fetch_projects() -> Result<Vec<Project>, ServiceError> {
@@ -53,13 +54,21 @@ async fn find_thumbnail(movie_dir_output_path: &Path) -> Option<PathBuf> {
     }
 }
 
+/// Long exposure image formats a browser can show as project thumbnail. TIFF and EXR renders are
+/// found through the PNG preview written next to them.
+const THUMBNAIL_IMAGE_EXTENSIONS: [&str; 4] = [".png", ".jpg", ".webp", ".avif"];
+
 async fn find_long_exposure_image(path: &Path) -> Option<PathBuf> {
     let mut sub_dir_stream = fs::read_dir(path).await.ok()?;
     while let Some(sub_entry) = sub_dir_stream.next_entry().await.ok()? {
         let sub_path = sub_entry.path();
         if sub_path.is_file() {
             if let Some(file_name) = sub_path.file_name().and_then(|name| name.to_str()) {
-                if file_name.starts_with("long_exposure_image_") && file_name.ends_with(".png") {
+                if file_name.starts_with("long_exposure_image_")
+                    && THUMBNAIL_IMAGE_EXTENSIONS
+                        .iter()
+                        .any(|extension| file_name.ends_with(extension))
+                {
                     return Some(sub_path);
                 }
             }
@@ -92,6 +101,8 @@ pub async fn delete_project_by_id(project_id: &str) -> Result<(), ServiceError>
         }
     }
 
+    invalidate_accumulation_state(project_id);
+
     // Delete the project directory
     if project_dir_path.exists() && project_dir_path.is_dir() {
         fs::remove_dir_all(project_dir_path).await?;
@@ -167,12 +178,13 @@ pub async fn process_upload(
 
     // Create directory for cut images
     let cut_images_save_dir_path =
-        output_dir.join(format!("{}/frames/", video_id.to_string()));
+        output_dir.join(format!("{}/frames/", video_id));
 
     // If a folder for the cut images already exists, delete it
     if fs::try_exists(&cut_images_save_dir_path).await? {
         debug!("Frames directory for project {} already exists, deleting its frames!", video_id);
         fs::remove_dir_all(&cut_images_save_dir_path).await?;
+        invalidate_accumulation_state(&video_id.to_string());
     }
     fs::create_dir_all(&cut_images_save_dir_path).await?;
 
@@ -188,16 +200,16 @@ pub async fn process_upload(
         .ok_or("Invalid webp output path")?
         .to_string();
 
-    debug!("Video file extension: {}", video_file_extension.to_string());
+    debug!("Video file extension: {}", video_file_extension);
     debug!("Upload save path: {}", uploaded_movie_save_file_path.to_str().unwrap());
-    debug!("FPS {}", fps.to_string());
+    debug!("FPS {}", fps);
     debug!("Scale {}", scale);
 
 
     Command::new("ffmpeg")
         .args([
             "-i",
-            &uploaded_movie_save_file_path.to_str().unwrap(), // Input file path
+            uploaded_movie_save_file_path.to_str().unwrap(), // Input file path
             "-threads",
             "0", // Use optimal amount of threads
             "-vf",
@@ -226,7 +238,12 @@ pub async fn process_upload(
         ])
         .output()
         .await
-        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("There was an error calling FFMPEG {}", err)))?;
+        .map_err(|err| std::io::Error::other(format!("There was an error calling FFMPEG {}", err)))?;
+
+    // New projects blend in linear light, existing ones keep the color space they were created with
+    let blend_color_space = metadata
+        .as_ref()
+        .map_or(BlendColorSpace::default(), |existing_metadata| existing_metadata.blend_color_space);
 
     // Save metadata to a file
     let metadata = ProjectMetadata {
@@ -235,6 +252,7 @@ pub async fn process_upload(
         scale,
         video_file_extension,
         latest_long_exposure_image_name: None,
+        blend_color_space,
     };
 
     save_project_metadata(&metadata, &video_id.to_string())?;
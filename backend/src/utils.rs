@@ -66,7 +66,7 @@ pub async fn convert_image_path_to_serving_url(image_path: &PathBuf) -> String {
     let path_str = absolute_path.to_str().unwrap().replace("\\", "/");
 
     // Split the path at /media/ and take the second part
-    let clean_path = path_str.splitn(2, "/media/").nth(1).unwrap_or(&path_str);
+    let clean_path = path_str.split_once("/media/").map_or(path_str.as_str(), |(_, path)| path);
 
     // Remove leading slashes
     let final_path = clean_path.trim_start_matches('/');
@@ -77,14 +77,14 @@ pub async fn convert_image_path_to_serving_url(image_path: &PathBuf) -> String {
 
 pub async fn read_metadata_from_project(project_id : &String) -> Result<ProjectMetadata, MetadataError>{
     let output_dir = get_output_dir();
-    let metadata_path = output_dir.join(&project_id).join("metadata.json");
+    let metadata_path = output_dir.join(project_id).join("metadata.json");
     let metadata_bytes = fs::read(&metadata_path).await?;
-    return serde_json::from_slice(&metadata_bytes).map_err(MetadataError::SerdeError);
+    serde_json::from_slice(&metadata_bytes).map_err(MetadataError::SerdeError)
 }
 
 pub fn save_project_metadata(metadata: &ProjectMetadata, project_id: &String) -> Result<(), MetadataError> {
     let output_dir = get_output_dir();
-    let metadata_path = output_dir.join(&project_id).join("metadata.json");
+    let metadata_path = output_dir.join(project_id).join("metadata.json");
 
     // Open the file with write and truncate options
     let mut file = std::fs::OpenOptions::new()
@@ -103,7 +103,7 @@ pub fn save_project_metadata(metadata: &ProjectMetadata, project_id: &String) ->
     Ok(())
 }
 
-pub fn generate_timestamped_path(base_path: &PathBuf, base_name: &str, extension: &str) -> PathBuf {
+pub fn generate_timestamped_path(base_path: &Path, base_name: &str, extension: &str) -> PathBuf {
     let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
     base_path.join(format!("{}_{}.{}", base_name, timestamp, extension))
 }
\ No newline at end of file
@@ -59,7 +59,7 @@ async function apiCall<T>(
 
 export const api = {
     get: <T>(endpoint: string) => apiCall<T>(endpoint),
-    post: <T>(endpoint: string, body: any) => apiCall<T>(endpoint, 'POST', body),
+    post: <T>(endpoint: string, body: any, headers?: HeadersInit) => apiCall<T>(endpoint, 'POST', body, headers),
     put: <T>(endpoint: string, body: any) => apiCall<T>(endpoint, 'PUT', body),
     delete: <T>(endpoint: string) => apiCall<T>(endpoint, 'DELETE'),
 };
@@ -103,6 +103,7 @@ export const endpoints = {
     projects: "/projects",
     specificProject: (projectId: string) => `/projects/${projectId}`,
     createLongExposureImage: (projectId : string) => `/projects/${projectId}/createLongExposureImage`,
+    projectMasks: (projectId: string) => `/projects/${projectId}/masks`,
     frameThumbnail: (projectId: string, frameNumber: number) => 
       `/outputs/${projectId}/frames/ffout_thumbnail_${frameNumber.toString().padStart(4, '0')}.webp`,
     videoFile: (projectId: string, fileExtension: string) => 
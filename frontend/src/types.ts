@@ -17,10 +17,202 @@ export interface Frame {
 export interface FrameToInclude {
     frame_number: number;
     frame_weight: number;
+    freeze?: boolean;
+}
+
+export type TrailFade = 'toward_first' | 'toward_last';
+
+export type ClippingCenter = 'mean' | 'median';
+
+export interface RelativePoint {
+    x: number;
+    y: number;
+}
+
+export type SliceLayout =
+    | { type: 'vertical' }
+    | { type: 'horizontal' }
+    | { type: 'radial'; center?: RelativePoint }
+    | { type: 'gradient'; start: RelativePoint; end: RelativePoint };
+
+export type BlendMode =
+    | 'weighted_mean'
+    | 'lighten'
+    | 'darken'
+    | 'median'
+    | 'screen'
+    | 'additive'
+    | { light_trails: { half_life?: number; fade?: TrailFade } }
+    | { sigma_clipping: { kappa?: number; iterations?: number; center?: ClippingCenter } }
+    | { stroboscopic: { every?: number; opacity?: number; threshold?: number; feather?: number } }
+    | { time_slice: { layout?: SliceLayout; blend?: number } };
+
+export type WeightingCurve =
+    | { type: 'none' }
+    | { type: 'power'; exponent: number }
+    | { type: 'threshold'; threshold: number; knee: number }
+    | { type: 'smoothstep'; low: number; high: number };
+
+export type BlendColorSpace = 'linear' | 'srgb';
+
+export interface ColorKey {
+    hue_start: number;
+    hue_end: number;
+    min_saturation?: number;
+    max_saturation?: number;
+    boost: number;
+    softness?: number;
+}
+
+export interface WeightingSettings {
+    curve?: WeightingCurve;
+    luma?: 'rec601' | 'rec709';
+    color_keys?: ColorKey[];
+}
+
+export type OutputFormat =
+    | { type: 'png' }
+    | { type: 'png16' }
+    | { type: 'tiff16' }
+    | { type: 'exr' }
+    | { type: 'jpeg'; quality?: number }
+    | { type: 'webp'; lossless?: boolean; quality?: number }
+    | { type: 'avif'; quality?: number; speed?: number };
+
+export type ToneMapping =
+    | { type: 'clip' }
+    | { type: 'exposure'; stops: number }
+    | { type: 'reinhard'; white_point?: number }
+    | { type: 'filmic' };
+
+export interface OutputSettings {
+    format?: OutputFormat;
+    tone_mapping?: ToneMapping;
+    width?: number;
+    height?: number;
+    max_dimension?: number;
+}
+
+export type AlignmentMethod = 'translation' | 'translation_rotation';
+
+export interface AlignmentSettings {
+    method?: AlignmentMethod;
+    reference_frame?: number;
+    max_rotation_degrees?: number;
+}
+
+export interface MaskSettings {
+    name: string;
+    key_frame?: number;
+    feather?: number;
+    invert?: boolean;
+}
+
+export interface FreezeFrameSettings {
+    threshold?: number;
+    feather?: number;
+}
+
+export type DimensionPolicy = 'reject' | 'resample' | 'crop';
+
+export interface CropRectangle {
+    x: number;
+    y: number;
+    width: number;
+    height: number;
+}
+
+export type Rotation = 'none' | 'cw90' | 'cw180' | 'cw270';
+
+export interface FrameRange {
+    start: number;
+    end: number;
+    step?: number;
+}
+
+export type KeyframeInterpolation = 'linear' | 'ease_in_out' | 'hold';
+
+export interface WeightKeyframe {
+    frame: number;
+    weight: number;
+    interpolation?: KeyframeInterpolation;
 }
 
 export interface CreateLongExposureImageRequest {
-    frames_to_include: FrameToInclude[];
+    frames_to_include?: FrameToInclude[];
+    frame_ranges?: FrameRange[];
+    weight_keyframes?: WeightKeyframe[];
+    blend_mode?: BlendMode;
+    weighting?: WeightingSettings;
+    color_space?: BlendColorSpace;
+    output?: OutputSettings;
+    alignment?: AlignmentSettings;
+    mask?: MaskSettings;
+    freeze_frame?: FreezeFrameSettings;
+    preview?: boolean;
+    dimension_policy?: DimensionPolicy;
+    tile_rows?: number;
+    crop?: CropRectangle;
+    rotation?: Rotation;
+    adjustments?: Adjustment[];
+    animation?: AnimationSettings;
+}
+
+export type Animation =
+    | { type: 'rolling'; window: number; step?: number }
+    | { type: 'build_up'; every?: number };
+
+export type AnimationFormat = 'mp4' | 'webm' | 'gif';
+
+export type AnimationSettings = Animation & {
+    format?: AnimationFormat;
+    fps?: number;
+};
+
+export interface CurvePoint {
+    input: number;
+    output: number;
+}
+
+export type Adjustment =
+    | { type: 'exposure'; ev: number }
+    | { type: 'levels'; black_point?: number; white_point?: number; gamma?: number }
+    | { type: 'curve'; points: CurvePoint[] }
+    | { type: 'saturation'; amount: number }
+    | { type: 'white_balance'; temperature?: number; tint?: number }
+    | { type: 'unsharp_mask'; radius: number; amount: number; threshold?: number }
+    | { type: 'vignette'; amount: number; midpoint?: number };
+
+export interface RejectionStatistics {
+    rejected_samples: number;
+    total_samples: number;
+}
+
+export interface CreateLongExposureImageResponse {
+    image_url: string;
+    rejection?: RejectionStatistics;
+    animation_url?: string;
+}
+
+export interface RenderVariant extends Partial<CreateLongExposureImageRequest> {
+    label?: string;
+}
+
+export interface CreateLongExposureImageVariantsRequest {
+    base: CreateLongExposureImageRequest;
+    variants: RenderVariant[];
+    contact_sheet?: boolean;
+}
+
+export interface VariantResponse {
+    label: string;
+    image_url: string;
+    rejection?: RejectionStatistics;
+}
+
+export interface CreateLongExposureImageVariantsResponse {
+    variants: VariantResponse[];
+    contact_sheet_url?: string;
 }
 
 export interface ProjectDataResponse {
@@ -29,6 +221,16 @@ export interface ProjectDataResponse {
     scale: string;
     video_file_extension: string;
     latest_long_exposure_image_name: string | null;
+    blend_color_space: BlendColorSpace;
+}
+
+export interface UploadMaskResponse {
+    message: string;
+    mask_name: string;
+}
+
+export interface GetMasksResponse {
+    masks: string[];
 }
 
 export interface UploadResponse {